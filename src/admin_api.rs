@@ -1,20 +1,31 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use http::StatusCode;
 use kube::ResourceExt;
-use kube_quantity::ParsedQuantity;
 use progenitor_client::ResponseValue;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use tracing::{info, warn};
 
 use crate::{
-    admin_api::client::types::{GetKeyShowSecretKey, UpdateBucketBody, UpdateBucketBodyQuotas},
-    resources::{AccessKey, Bucket, BucketQuotas, Garage},
-    Error, Result,
+    admin_api::client::types::{
+        GetKeyShowSecretKey, UpdateBucketBody, UpdateBucketBodyQuotas,
+        UpdateBucketBodyWebsiteAccess,
+    },
+    quantity::quantity_to_bytes,
+    resources::{
+        AccessKeyPermissions, BucketQuotas, BucketSpec, BucketWebsite, Garage, GarageConfig,
+    },
+    Error, Metrics, Result,
 };
 
 use self::client::types::{
-    AddKeyBody, AllowBucketKeyBody, AllowBucketKeyBodyPermissions, BucketInfo, CreateBucketBody,
-    KeyInfo, LayoutVersion, NodeRoleChange, NodeRoleUpdate,
+    AddKeyBody, AllowBucketKeyBody, AllowBucketKeyBodyPermissions, BucketInfo, ClusterLayout,
+    CreateBucketBody, DenyBucketKeyBody, DenyBucketKeyBodyPermissions, KeyInfo, LayoutVersion,
+    NodeRoleChange, NodeRoleUpdate,
 };
 
 /// Autogenerated client for the garage admin API using its corresponding openapi spec.
@@ -23,85 +34,209 @@ mod client {
     include!(concat!(env!("OUT_DIR"), "/garage-admin-client.rs"));
 }
 
-pub struct GarageAdmin<'a> {
-    garage: &'a Garage,
+/// A handle to the admin API of a garage instance.
+///
+/// Creating a handle requires fetching the admin token, so a single handle should be shared
+/// for the duration of a reconcile pass.
+pub struct GarageAdmin {
+    garage: Garage,
     client: client::Client,
+    layout_lock: LayoutLock,
+    metrics: Metrics,
 }
 
-impl<'a> GarageAdmin<'a> {
-    pub fn with_secret(garage: &'a Garage, token: &str) -> Result<GarageAdmin<'a>> {
-        // All requests must be authenticated using bearer auth
-        let headers = {
-            let mut headers = HeaderMap::new();
-            let mut auth = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
-            auth.set_sensitive(true);
+/// A lock serializing the layout changes made by the operator to a single garage
+type LayoutLock = Arc<tokio::sync::Mutex<()>>;
 
-            headers.insert(AUTHORIZATION, auth);
-            headers
-        };
+/// Long-lived HTTP clients for the admin APIs of all garages.
+///
+/// The generated client authenticates through the default headers of its `reqwest::Client`, so
+/// one is kept per garage and admin token until the token can be attached per request (see
+/// TODO.md). Connections are pooled per host, so reconciles reuse them all the same.
+///
+/// The locks serializing layout changes are kept here as well, one per garage UID, so that a
+/// slow garage only ever holds up changes to its own layout.
+#[derive(Default)]
+pub struct AdminClients {
+    clients: Mutex<HashMap<String, (String, reqwest::Client)>>,
+    layout_locks: Mutex<HashMap<String, LayoutLock>>,
+}
 
-        // Use a client to handle setting common request parameters
-        // TODO: Handle error here nicely
-        let client = reqwest::Client::builder()
-            .connect_timeout(Duration::from_secs(5))
-            .default_headers(headers)
-            .build()
-            .unwrap();
-
-        let admin_port = garage.spec.config.ports.admin;
-        let url = format!(
-            "http://{}.{}.svc.cluster.local:{}/v1",
-            garage.prefixed_name("api"),
-            garage.namespace().unwrap(),
-            admin_port,
-        );
+impl AdminClients {
+    /// Get the client for a garage, creating a new one if its admin token changed
+    fn get(&self, garage: &Garage, token: &str) -> Result<reqwest::Client> {
+        let mut clients = self.clients.lock().unwrap();
+        match clients.get(&client_key(garage)) {
+            Some((existing, client)) if existing == token => Ok(client.clone()),
+            _ => {
+                let client = admin_http_client(garage, token)?;
+                clients.insert(client_key(garage), (token.to_string(), client.clone()));
+
+                Ok(client)
+            }
+        }
+    }
+
+    /// Get the lock serializing layout changes to a garage
+    fn layout_lock(&self, garage: &Garage) -> LayoutLock {
+        let uid = garage.uid().unwrap_or_else(|| client_key(garage));
+
+        self.layout_locks
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_default()
+            .clone()
+    }
+
+    /// Drop the client and layout lock of a garage which no longer exists
+    pub fn forget(&self, garage: &Garage) {
+        self.clients.lock().unwrap().remove(&client_key(garage));
+        if let Some(uid) = garage.uid() {
+            self.layout_locks.lock().unwrap().remove(&uid);
+        }
+    }
+}
+
+/// The key identifying the client of a garage
+fn client_key(garage: &Garage) -> String {
+    format!(
+        "{}/{}",
+        garage.namespace().unwrap_or_default(),
+        garage.name_any()
+    )
+}
+
+/// Build a pooled HTTP client which authenticates every request with an admin token
+fn admin_http_client(garage: &Garage, token: &str) -> Result<reqwest::Client> {
+    let illegal = |reason: String| Error::IllegalGarage(garage.name_any(), reason);
+
+    // All requests must be authenticated using bearer auth
+    let headers = {
+        let mut headers = HeaderMap::new();
+        let mut auth = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| {
+            illegal("admin token contains characters which are not allowed in a header".into())
+        })?;
+        auth.set_sensitive(true);
+
+        headers.insert(AUTHORIZATION, auth);
+        headers
+    };
+
+    // Use a client to handle setting common request parameters, keeping connections
+    // alive in between reconciles
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .default_headers(headers)
+        .build()
+        .map_err(|e| illegal(format!("could not build admin API client: {e}")))
+}
+
+impl GarageAdmin {
+    pub fn with_secret(
+        garage: &Garage,
+        token: &str,
+        clients: &AdminClients,
+        metrics: &Metrics,
+    ) -> Result<GarageAdmin> {
+        let client = clients.get(garage, token)?;
+
+        let url = format!("{}/v1", garage.service_url(garage.spec.config.ports.admin));
 
         Ok(GarageAdmin {
-            garage,
+            garage: garage.clone(),
             client: client::Client::new_with_client(&url, client),
+            layout_lock: clients.layout_lock(garage),
+            metrics: metrics.clone(),
         })
     }
 
-    pub async fn layout_instance(&self, capacity: i64) -> Result<bool> {
+    /// The generated client, counting a request to the admin API for the given method
+    fn api(&self, method: &str) -> &client::Client {
+        self.metrics.admin_request(&self.garage, method);
+        &self.client
+    }
+
+    /// Count the nodes known to the cluster, returning the amount of healthy and total nodes
+    pub async fn get_node_counts(&self) -> Result<(i64, i64)> {
+        let nodes = self
+            .api("get_nodes")
+            .get_nodes()
+            .await?
+            .into_inner()
+            .known_nodes;
+        let healthy = nodes.iter().filter(|node| node.is_up).count();
+
+        Ok((healthy as i64, nodes.len() as i64))
+    }
+
+    /// Fetch the ID of the node serving the admin API along with the current layout version
+    pub async fn get_layout_version(&self) -> Result<(String, i64)> {
+        let nodes = self.api("get_nodes").get_nodes().await?.into_inner();
+
+        Ok((nodes.node, nodes.layout.version))
+    }
+
+    pub async fn layout_instance(&self, capacity: i64, zone: &str) -> Result<bool> {
+        // Only one layout change may be staged and applied at a time, otherwise concurrent
+        // reconciles could each try to apply the same layout version
+        let _guard = self.layout_lock.lock().await;
+
         // Get the current status of the instance, failing if it is unhealthy
-        let nodes = self.client.get_nodes().await?.into_inner();
+        let nodes = self.api("get_nodes").get_nodes().await?.into_inner();
+
+        // Leave nodes which were given a role by hand alone
+        let node_id = nodes.node;
+        let tags = node_tags(&self.garage);
+        if externally_managed(&nodes.layout, &node_id, &tags) {
+            info!(
+                r#"Not laying out garage "{}", its node is managed outside of the operator"#,
+                self.garage.name_any()
+            );
+            return Ok(true);
+        }
 
         // If the node has been laid out already, then skip
         // TODO: Write out a message
-        let node_id = nodes.node;
         if nodes.layout.version != 0 {
+            // Changing the zone requires a manual layout change, so let the user know
+            let mismatched = nodes
+                .layout
+                .roles
+                .iter()
+                .filter(|role| role.id == node_id)
+                .find(|role| role.zone != zone);
+            if let Some(role) = mismatched {
+                warn!(
+                    r#"Garage "{}" is laid out in zone "{}", but is configured for zone "{zone}""#,
+                    self.garage.name_any(),
+                    role.zone,
+                );
+            }
+
             return Ok(true);
         }
 
-        // Add a layout request if we did not already
-        let staged = nodes
-            .layout
-            .staged_role_changes
-            .iter()
-            .any(|change| match change {
-                NodeRoleChange::Update(NodeRoleUpdate { id, .. }) => *id == node_id,
-                _ => false,
-            });
-
-        if !staged {
+        // Add a layout request if an equivalent one is not already pending
+        let update = NodeRoleUpdate {
+            capacity: Some(capacity),
+            id: node_id,
+            tags: tags.into_iter().chain(label_tags(&self.garage)).collect(),
+            zone: zone.to_string(),
+        };
+        if !is_staged(&nodes.layout.staged_role_changes, &update) {
             let _layout = self
-                .client
-                .add_layout(&vec![NodeRoleChange::Update(NodeRoleUpdate {
-                    capacity: Some(capacity),
-                    id: node_id,
-                    tags: vec![
-                        "owned-by/garage-operator".into(),
-                        format!("garage-instance/{}", self.garage.name_any()),
-                    ],
-                    zone: self.garage.spec.config.region.clone(),
-                })])
+                .api("add_layout")
+                .add_layout(&vec![NodeRoleChange::Update(update)])
                 .await?;
         }
 
-        // Actually apply the layout
+        // Actually apply the layout on top of the current one
         let _apply = self
-            .client
-            .apply_layout(&LayoutVersion { version: 1 })
+            .api("apply_layout")
+            .apply_layout(&next_layout_version(nodes.layout.version))
             .await?;
 
         // TODO: Write out a message
@@ -110,77 +245,309 @@ impl<'a> GarageAdmin<'a> {
 }
 
 // Bucket related actions
-impl GarageAdmin<'_> {
-    /// Create a bucket
-    pub async fn create_bucket(&self, name: &str) -> Result<BucketInfo> {
-        self.client
+impl GarageAdmin {
+    /// Create a bucket, returning its ID
+    pub async fn create_bucket(&self, name: &str) -> Result<String> {
+        let bucket = self
+            .api("create_bucket")
             .create_bucket(&CreateBucketBody {
                 global_alias: Some(name.to_string()),
                 local_alias: None,
             })
             .await
             .map(ResponseValue::into_inner)
-            .map_err(Error::NetworkError)
+            .map_err(Error::from)?;
+
+        bucket_id(name, bucket)
     }
 
     /// Fetches bucket information from garage by its name, if it exists
     pub async fn get_bucket_by_name(&self, name: &str) -> Result<Option<BucketInfo>> {
-        match self
-            .client
+        let response = self
+            .api("get_bucket_info")
             .get_bucket_info(Some(name), None)
+            .await;
+
+        found(response, StatusCode::NOT_FOUND, |bucket| {
+            bucket.id.is_none()
+        })
+    }
+
+    /// Fetches bucket information from garage by its ID
+    pub async fn get_bucket_by_id(&self, id: &str) -> Result<BucketInfo> {
+        self.api("get_bucket_info")
+            .get_bucket_info(None, Some(id))
             .await
             .map(ResponseValue::into_inner)
-        {
-            // The admin API returns an empty bucket if you ask for one that does not exist...
-            Ok(BucketInfo { id: None, .. }) => Ok(None),
-            Ok(bucket) => Ok(Some(bucket)),
-
-            // If it errors, it could be because it doesn't exist
-            Err(e) => {
-                if matches!(e.status(), Some(StatusCode::NOT_FOUND)) {
-                    Ok(None)
-                } else {
-                    Err(Error::NetworkError(e))
-                }
-            }
-        }
+            .map_err(Error::from)
     }
 
-    /// Set the quotas for a bucket
-    pub async fn set_bucket_quotas(&self, id: &str, quotas: &BucketQuotas) -> Result<()> {
-        let max_size = quotas
-            .max_size
-            .as_ref()
-            .and_then(|max_size| ParsedQuantity::try_from(max_size).unwrap().to_bytes_i64()); // TODO: Remove unwrap
+    /// Check that garage can apply everything configured for a bucket
+    ///
+    /// Quotas which garage cannot store are rejected instead of being silently skipped, as are
+    /// thresholds which would misreport the usage of the bucket.
+    pub fn check_bucket_support(&self, name: &str, spec: &BucketSpec) -> Result<()> {
+        max_objects(&spec.quotas).map_err(|e| Error::IllegalBucket(name.to_string(), e))?;
+        self.bucket_max_size(name, spec)?;
+        spec.quotas
+            .validate()
+            .map_err(|e| Error::IllegalBucket(name.to_string(), e))?;
 
-        self.client
-            .update_bucket(
-                id,
-                &UpdateBucketBody {
-                    quotas: Some(UpdateBucketBodyQuotas {
-                        max_objects: quotas.max_object_count.map(|m| m as i64),
-                        max_size,
-                    }),
-                    website_access: None,
-                },
-            )
-            .await?;
+        Ok(())
+    }
+
+    /// The size quota of a bucket in bytes, as of the current capacity of this garage
+    ///
+    /// Quotas given as a percentage depend on the capacity, so they must be resolved again
+    /// whenever it changes.
+    pub fn bucket_max_size(&self, name: &str, spec: &BucketSpec) -> Result<Option<i64>> {
+        let capacity = self.garage.status.as_ref().map(|s| s.capacity);
+        max_size(&spec.quotas, capacity).map_err(|e| Error::IllegalBucket(name.to_string(), e))
+    }
+
+    /// Build the full configuration of a bucket, as a single update for garage
+    ///
+    /// Quotas and website access are sent together so that a bucket is never left
+    /// partially configured.
+    pub fn bucket_update(&self, name: &str, spec: &BucketSpec) -> Result<UpdateBucketBody> {
+        let max_size = self.bucket_max_size(name, spec)?;
+        let max_objects =
+            max_objects(&spec.quotas).map_err(|e| Error::IllegalBucket(name.to_string(), e))?;
+
+        Ok(UpdateBucketBody {
+            quotas: Some(UpdateBucketBodyQuotas {
+                max_objects,
+                max_size,
+            }),
+            website_access: Some(website_access(&spec.website, &self.garage.spec.config)),
+        })
+    }
+
+    /// The update needed for a bucket to match its spec, if garage reports anything different
+    pub fn bucket_drift(
+        &self,
+        name: &str,
+        spec: &BucketSpec,
+        info: &BucketInfo,
+    ) -> Result<Option<UpdateBucketBody>> {
+        let update = self.bucket_update(name, spec)?;
+
+        Ok(bucket_drifted(info, &update).then_some(update))
+    }
+
+    /// Apply the full configuration of a bucket in a single request
+    pub async fn apply_bucket_config(&self, id: &str, update: &UpdateBucketBody) -> Result<()> {
+        self.api("update_bucket").update_bucket(id, update).await?;
 
         Ok(())
     }
 }
 
+/// Whether anything set by an update differs from what garage reports for a bucket
+fn bucket_drifted(info: &BucketInfo, update: &UpdateBucketBody) -> bool {
+    let quotas = info.quotas.as_ref();
+    let quotas_drifted = update.quotas.as_ref().is_some_and(|wanted| {
+        quotas.and_then(|q| q.max_size) != wanted.max_size
+            || quotas.and_then(|q| q.max_objects) != wanted.max_objects
+    });
+
+    // The documents only matter while the website is enabled
+    let website = info.website_config.as_ref();
+    let website_drifted = update.website_access.as_ref().is_some_and(|wanted| {
+        let enabled = wanted.enabled.unwrap_or_default();
+        info.website_access.unwrap_or_default() != enabled
+            || (enabled
+                && (website.and_then(|w| w.index_document.as_ref())
+                    != wanted.index_document.as_ref()
+                    || website.and_then(|w| w.error_document.as_ref())
+                        != wanted.error_document.as_ref()))
+    });
+
+    quotas_drifted || website_drifted
+}
+
+/// The ID of a bucket reported by garage.
+///
+/// The admin API marks the ID as optional, so a bucket without one is reported as an error
+/// instead of bringing down the controller.
+pub(crate) fn bucket_id(name: &str, bucket: BucketInfo) -> Result<String> {
+    bucket.id.ok_or_else(|| {
+        Error::IllegalBucket(
+            name.to_string(),
+            "garage did not report an ID for the bucket".into(),
+        )
+    })
+}
+
+/// Normalize the ways in which garage reports that a looked up resource does not exist.
+///
+/// Depending on the endpoint, garage either responds with an error status or with an empty
+/// object, so both are treated as the resource not being found.
+fn found<T>(
+    response: std::result::Result<ResponseValue<T>, progenitor_client::Error>,
+    not_found: StatusCode,
+    is_empty: impl FnOnce(&T) -> bool,
+) -> Result<Option<T>> {
+    match response.map(ResponseValue::into_inner) {
+        Ok(value) if is_empty(&value) => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.status() == Some(not_found) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether an equivalent role change for a node is already waiting to be applied.
+fn is_staged(staged: &[NodeRoleChange], update: &NodeRoleUpdate) -> bool {
+    staged.iter().any(|change| match change {
+        NodeRoleChange::Update(staged) => {
+            staged.id == update.id
+                && staged.zone == update.zone
+                && staged.capacity == update.capacity
+                && staged.tags == update.tags
+        }
+        _ => false,
+    })
+}
+
+/// The tags marking the node of a garage as managed by the operator.
+fn node_tags(garage: &Garage) -> Vec<String> {
+    let instance_tag = format!("garage-instance/{}", garage.name_any());
+    let mut tags = garage.spec.config.node_tags.clone();
+    if !tags.contains(&instance_tag) {
+        tags.push(instance_tag);
+    }
+
+    tags
+}
+
+/// The tags mirroring the selected labels of a garage onto its node, as `<label>=<value>`.
+fn label_tags(garage: &Garage) -> Vec<String> {
+    let labels = garage.labels();
+    garage
+        .spec
+        .config
+        .node_tag_labels
+        .iter()
+        .filter_map(|label| Some(format!("{label}={}", labels.get(label)?)))
+        .collect()
+}
+
+/// Whether a node was given a role, either in the layout or staged for it, by something other
+/// than the operator.
+///
+/// Roles are managed by the operator when they carry any of its tags.
+fn externally_managed(layout: &ClusterLayout, node_id: &str, tags: &[String]) -> bool {
+    let current = layout
+        .roles
+        .iter()
+        .filter(|role| role.id == node_id)
+        .map(|role| &role.tags);
+    let staged = layout
+        .staged_role_changes
+        .iter()
+        .filter_map(|change| match change {
+            NodeRoleChange::Update(update) if update.id == node_id => Some(&update.tags),
+            _ => None,
+        });
+
+    current
+        .chain(staged)
+        .any(|role_tags| !role_tags.iter().any(|tag| tags.contains(tag)))
+}
+
+/// The layout version to apply in order to replace the current layout.
+///
+/// Garage rejects applying any version other than the one directly following the current.
+fn next_layout_version(current: i64) -> LayoutVersion {
+    LayoutVersion {
+        version: current + 1,
+    }
+}
+
+/// The size quota of a bucket in bytes, resolving percentages against the garage capacity.
+fn max_size(
+    quotas: &BucketQuotas,
+    capacity: Option<i64>,
+) -> std::result::Result<Option<i64>, String> {
+    let Some(max_size) = &quotas.max_size else {
+        return Ok(None);
+    };
+
+    if let Some(percent) = max_size.0.strip_suffix('%') {
+        let percent: f64 = percent
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid max size {}", max_size.0))?;
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(format!(
+                "max size {} must be above 0% and at most 100%",
+                max_size.0
+            ));
+        }
+
+        let capacity = capacity
+            .filter(|capacity| *capacity > 0)
+            .ok_or_else(|| format!("max size {} needs a known garage capacity", max_size.0))?;
+        return Ok(Some((capacity as f64 * percent / 100.0) as i64));
+    }
+
+    quantity_to_bytes(max_size)
+        .map(Some)
+        .map_err(|e| format!("invalid max size: {e}"))
+}
+
+/// The object count quota of a bucket, as the signed integer that garage expects.
+fn max_objects(quotas: &BucketQuotas) -> std::result::Result<Option<i64>, String> {
+    quotas
+        .max_object_count
+        .map(|max| i64::try_from(max).map_err(|_| format!("max object count {max} is too large")))
+        .transpose()
+}
+
+/// Build the website access for a bucket.
+///
+/// A missing website config means that the website is disabled, so this always
+/// produces an explicit choice in order to undo any previously enabled access.
+fn website_access(
+    website: &BucketWebsite,
+    garage_config: &GarageConfig,
+) -> UpdateBucketBodyWebsiteAccess {
+    if !website.enabled {
+        return UpdateBucketBodyWebsiteAccess {
+            enabled: Some(false),
+            index_document: None,
+            error_document: None,
+        };
+    }
+
+    // Garage requires an index document when enabling website access, so fall back
+    // to the defaults of the owning garage
+    UpdateBucketBodyWebsiteAccess {
+        enabled: Some(true),
+        index_document: Some(
+            website
+                .index_document
+                .clone()
+                .unwrap_or_else(|| garage_config.web_index.clone()),
+        ),
+        error_document: website
+            .error_document
+            .clone()
+            .or_else(|| garage_config.web_error_document.clone()),
+    }
+}
+
 // Access key related ops
-impl GarageAdmin<'_> {
+impl GarageAdmin {
     /// Create a new API key
     pub async fn create_key(&self, name: &str) -> Result<KeyInfo> {
-        self.client
+        self.api("add_key")
             .add_key(&AddKeyBody {
                 name: Some(name.to_string()),
             })
             .await
             .map(ResponseValue::into_inner)
-            .map_err(Error::NetworkError)
+            .map_err(Error::from)
     }
 
     /// Look up a key by its name
@@ -190,8 +557,8 @@ impl GarageAdmin<'_> {
         fetch_secret: bool,
     ) -> Result<Option<KeyInfo>, Error> {
         // Ask garage for the key
-        match self
-            .client
+        let response = self
+            .api("get_key")
             .get_key(
                 None,
                 Some(name),
@@ -201,43 +568,122 @@ impl GarageAdmin<'_> {
                     GetKeyShowSecretKey::False
                 }),
             )
-            .await
-            .map(ResponseValue::into_inner)
-        {
-            // This API is somewhat painful
-            Ok(KeyInfo {
-                access_key_id: None,
-                ..
-            }) => Ok(None),
-            Ok(key) => {
-                if key.name == Some(name.to_string()) {
-                    Ok(Some(key))
-                } else {
-                    Ok(None)
-                }
-            }
+            .await;
 
-            // If it errors, it could be because it doesn't exist
-            Err(e) => {
-                if matches!(e.status(), Some(StatusCode::BAD_REQUEST)) {
-                    Ok(None)
-                } else {
-                    Err(Error::NetworkError(e))
-                }
-            }
+        // The search may match other keys as well, so make sure that we got the exact one
+        let key = found(response, StatusCode::BAD_REQUEST, |key| {
+            key.access_key_id.is_none()
+        })?;
+
+        Ok(key.filter(|key| key.name.as_deref() == Some(name)))
+    }
+
+    /// Fetch the permissions that a key actually has on a specific bucket
+    pub async fn get_key_permissions_for_bucket(
+        &self,
+        key_id: &str,
+        bucket_id: &str,
+    ) -> Result<AccessKeyPermissions> {
+        let key = self
+            .api("get_key")
+            .get_key(Some(key_id), None, Some(GetKeyShowSecretKey::False))
+            .await?
+            .into_inner();
+
+        // Keys without any access to the bucket are not listed at all
+        let permissions = key
+            .buckets
+            .into_iter()
+            .find(|b| b.id.as_deref() == Some(bucket_id))
+            .and_then(|b| b.permissions)
+            .map(|p| AccessKeyPermissions {
+                read: p.read.unwrap_or_default(),
+                write: p.write.unwrap_or_default(),
+                owner: p.owner.unwrap_or_default(),
+                all: false,
+            })
+            .unwrap_or_default();
+
+        Ok(permissions)
+    }
+
+    /// Revoke all permissions of a key for a specific bucket
+    pub async fn deny_key_for_bucket(&self, key_id: &str, bucket_id: &str) -> Result<()> {
+        self.api("deny_bucket_key")
+            .deny_bucket_key(&DenyBucketKeyBody {
+                access_key_id: key_id.to_string(),
+                bucket_id: bucket_id.to_string(),
+                permissions: DenyBucketKeyBodyPermissions {
+                    owner: true,
+                    read: true,
+                    write: true,
+                },
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revoke all permissions of a key for every bucket it has access to
+    pub async fn revoke_key(&self, key_id: &str) -> Result<()> {
+        let key = self
+            .api("get_key")
+            .get_key(Some(key_id), None, Some(GetKeyShowSecretKey::False))
+            .await?
+            .into_inner();
+
+        for bucket_id in key.buckets.into_iter().filter_map(|b| b.id) {
+            self.deny_key_for_bucket(key_id, &bucket_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Grant a key exactly the given permissions for a specific bucket, revoking any others
+    pub async fn set_key_permissions_for_bucket(
+        &self,
+        key_id: &str,
+        bucket_id: &str,
+        permissions: &AccessKeyPermissions,
+    ) -> Result<()> {
+        self.allow_key_for_bucket(key_id, bucket_id, permissions)
+            .await?;
+
+        // Allowing only ever adds permissions, so explicitly take away the ones not requested
+        let permissions = permissions.effective();
+        if !(permissions.read && permissions.write && permissions.owner) {
+            self.api("deny_bucket_key")
+                .deny_bucket_key(&DenyBucketKeyBody {
+                    access_key_id: key_id.to_string(),
+                    bucket_id: bucket_id.to_string(),
+                    permissions: DenyBucketKeyBodyPermissions {
+                        owner: !permissions.owner,
+                        read: !permissions.read,
+                        write: !permissions.write,
+                    },
+                })
+                .await?;
         }
+
+        Ok(())
     }
 
     /// Allow a key to be used for a specific bucket
-    pub async fn allow_key_for_bucket(&self, key: &AccessKey, bucket: &Bucket) -> Result<()> {
-        self.client
+    pub async fn allow_key_for_bucket(
+        &self,
+        key_id: &str,
+        bucket_id: &str,
+        permissions: &AccessKeyPermissions,
+    ) -> Result<()> {
+        let permissions = permissions.effective();
+        self.api("allow_bucket_key")
             .allow_bucket_key(&AllowBucketKeyBody {
-                access_key_id: key.status.as_ref().unwrap().id.to_string(),
-                bucket_id: bucket.status.as_ref().unwrap().id.to_string(),
+                access_key_id: key_id.to_string(),
+                bucket_id: bucket_id.to_string(),
                 permissions: AllowBucketKeyBodyPermissions {
-                    owner: key.spec.permissions.owner,
-                    read: key.spec.permissions.read,
-                    write: key.spec.permissions.write,
+                    owner: permissions.owner,
+                    read: permissions.read,
+                    write: permissions.write,
                 },
             })
             .await?;
@@ -245,3 +691,405 @@ impl GarageAdmin<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use serde_json::json;
+
+    use super::*;
+    use crate::admin_api::client::types::NodeClusterInfo;
+
+    fn garage() -> Garage {
+        let spec = serde_json::from_value(json!({
+            "storage": { "meta": "meta", "data": ["data"] },
+        }))
+        .unwrap();
+
+        Garage::new("garage", spec)
+    }
+
+    fn admin() -> GarageAdmin {
+        GarageAdmin {
+            garage: garage(),
+            client: client::Client::new("http://garage"),
+            layout_lock: Default::default(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    fn response<T>(value: T) -> std::result::Result<ResponseValue<T>, progenitor_client::Error> {
+        Ok(ResponseValue::new(value, StatusCode::OK, HeaderMap::new()))
+    }
+
+    fn error_response<T>(
+        status: StatusCode,
+    ) -> std::result::Result<ResponseValue<T>, progenitor_client::Error> {
+        Err(progenitor_client::Error::ErrorResponse(ResponseValue::new(
+            (),
+            status,
+            HeaderMap::new(),
+        )))
+    }
+
+    #[test]
+    fn empty_objects_are_not_found() {
+        let is_empty = |v: &Option<&str>| v.is_none();
+
+        assert_eq!(
+            found(response(None), StatusCode::NOT_FOUND, is_empty).unwrap(),
+            None
+        );
+        assert_eq!(
+            found(response(Some("id")), StatusCode::NOT_FOUND, is_empty).unwrap(),
+            Some(Some("id"))
+        );
+    }
+
+    #[test]
+    fn not_found_statuses_are_not_found() {
+        let is_empty = |_: &()| false;
+
+        // Only the status used by the endpoint for missing resources means not found
+        assert!(matches!(
+            found(
+                error_response(StatusCode::NOT_FOUND),
+                StatusCode::NOT_FOUND,
+                is_empty
+            ),
+            Ok(None)
+        ));
+        assert!(matches!(
+            found(
+                error_response(StatusCode::BAD_REQUEST),
+                StatusCode::BAD_REQUEST,
+                is_empty
+            ),
+            Ok(None)
+        ));
+        assert!(matches!(
+            found(
+                error_response(StatusCode::INTERNAL_SERVER_ERROR),
+                StatusCode::NOT_FOUND,
+                is_empty
+            ),
+            Err(Error::NetworkError(_))
+        ));
+    }
+
+    #[test]
+    fn buckets_without_id_are_an_error() {
+        let created: BucketInfo = serde_json::from_value(json!({ "id": "abc" })).unwrap();
+        assert_eq!(bucket_id("bucket", created).unwrap(), "abc");
+
+        let created: BucketInfo = serde_json::from_value(json!({})).unwrap();
+        assert!(matches!(
+            bucket_id("bucket", created),
+            Err(Error::IllegalBucket(name, _)) if name == "bucket"
+        ));
+    }
+
+    fn role_update(id: &str, zone: &str) -> NodeRoleUpdate {
+        NodeRoleUpdate {
+            capacity: Some(1024),
+            id: id.into(),
+            tags: vec!["owned-by/garage-operator".into()],
+            zone: zone.into(),
+        }
+    }
+
+    #[test]
+    fn admin_clients_are_reused_until_token_changes() {
+        let garage = garage();
+        let clients = AdminClients::default();
+
+        clients.get(&garage, "a").unwrap();
+        clients.get(&garage, "a").unwrap();
+        assert_eq!(clients.clients.lock().unwrap()[&client_key(&garage)].0, "a");
+
+        clients.get(&garage, "b").unwrap();
+        assert_eq!(clients.clients.lock().unwrap()[&client_key(&garage)].0, "b");
+
+        clients.forget(&garage);
+        assert!(clients.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_admin_tokens_are_an_error() {
+        let garage = garage();
+        let clients = AdminClients::default();
+
+        // Secrets written by hand easily end up with a trailing newline
+        for token in ["token\n", "tok\ren", "token\0"] {
+            assert!(
+                matches!(
+                    clients.get(&garage, token),
+                    Err(Error::IllegalGarage(name, _)) if name == "garage"
+                ),
+                "{token:?}"
+            );
+        }
+        assert!(clients.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn layout_locks_are_kept_per_garage() {
+        let with_uid = |uid: &str| {
+            let mut garage = garage();
+            garage.metadata.uid = Some(uid.into());
+            garage
+        };
+        let (first, second) = (with_uid("first"), with_uid("second"));
+        let clients = AdminClients::default();
+
+        // Laying out one garage should not hold up any other
+        let lock = clients.layout_lock(&first);
+        let _guard = lock.try_lock().unwrap();
+        assert!(clients.layout_lock(&first).try_lock().is_err());
+        assert!(clients.layout_lock(&second).try_lock().is_ok());
+
+        clients.forget(&first);
+        assert!(!clients.layout_locks.lock().unwrap().contains_key("first"));
+        assert!(clients.layout_locks.lock().unwrap().contains_key("second"));
+    }
+
+    #[test]
+    fn equivalent_changes_are_already_staged() {
+        let staged = vec![
+            NodeRoleChange::Update(role_update("a", "dc1")),
+            NodeRoleChange::Update(role_update("b", "dc1")),
+        ];
+
+        assert!(is_staged(&staged, &role_update("a", "dc1")));
+        assert!(is_staged(&staged, &role_update("b", "dc1")));
+
+        // Changes for other nodes, or differing changes for the same node, still need staging
+        assert!(!is_staged(&staged, &role_update("c", "dc1")));
+        assert!(!is_staged(&staged, &role_update("a", "dc2")));
+        assert!(!is_staged(&[], &role_update("a", "dc1")));
+    }
+
+    #[test]
+    fn only_owned_nodes_are_laid_out() {
+        let mut garage = garage();
+        garage.spec.config.node_tags = vec!["team/storage".into()];
+        let tags = node_tags(&garage);
+        assert_eq!(tags, ["team/storage", "garage-instance/garage"]);
+
+        let role = |id: &str, tags: &[&str]| NodeClusterInfo {
+            capacity: Some(1024),
+            id: id.into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            zone: "dc1".into(),
+        };
+        let staged = |id: &str, tags: &[&str]| {
+            NodeRoleChange::Update(NodeRoleUpdate {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                ..role_update(id, "dc1")
+            })
+        };
+        let layout = ClusterLayout {
+            version: 1,
+            roles: vec![
+                role("configured", &["team/storage"]),
+                role("instance", &["garage-instance/garage"]),
+                role("manual", &["gateway"]),
+                role("untagged", &[]),
+            ],
+            staged_role_changes: vec![
+                staged("staged-owned", &["team/storage"]),
+                staged("staged-manual", &["fast"]),
+            ],
+        };
+
+        for node in ["configured", "instance", "staged-owned", "new"] {
+            assert!(!externally_managed(&layout, node, &tags), "{node}");
+        }
+        for node in ["manual", "untagged", "staged-manual"] {
+            assert!(externally_managed(&layout, node, &tags), "{node}");
+        }
+    }
+
+    #[test]
+    fn selected_labels_become_node_tags() {
+        let mut garage = garage();
+        garage.spec.config.node_tag_labels =
+            vec!["app.kubernetes.io/part-of".into(), "missing".into()];
+        assert!(label_tags(&garage).is_empty());
+
+        garage.labels_mut().extend([
+            (
+                "app.kubernetes.io/part-of".to_string(),
+                "storage".to_string(),
+            ),
+            ("unselected".to_string(), "value".to_string()),
+        ]);
+        assert_eq!(label_tags(&garage), ["app.kubernetes.io/part-of=storage"]);
+
+        // Label tags say nothing about who manages a node
+        assert!(!node_tags(&garage).contains(&label_tags(&garage)[0]));
+    }
+
+    #[test]
+    fn layout_version_follows_current() {
+        assert_eq!(next_layout_version(0).version, 1);
+        assert_eq!(next_layout_version(1).version, 2);
+    }
+
+    #[test]
+    fn admin_requests_are_counted_per_method() {
+        let admin = admin();
+
+        admin.api("get_nodes");
+        admin.api("get_nodes");
+        admin.api("add_key");
+
+        let count = |method| {
+            admin
+                .metrics
+                .admin_requests
+                .with_label_values(&[method, "/garage"])
+                .get()
+        };
+        assert_eq!(count("get_nodes"), 2);
+        assert_eq!(count("add_key"), 1);
+        assert_eq!(count("update_bucket"), 0);
+    }
+
+    #[test]
+    fn max_object_count_must_fit_in_i64() {
+        let quotas = |max_object_count: u64| BucketQuotas {
+            max_object_count: Some(max_object_count),
+            ..Default::default()
+        };
+
+        assert_eq!(max_objects(&BucketQuotas::default()), Ok(None));
+        assert_eq!(max_objects(&quotas(i64::MAX as u64)), Ok(Some(i64::MAX)));
+        assert!(max_objects(&quotas(i64::MAX as u64 + 1)).is_err());
+        assert!(max_objects(&quotas(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn max_size_resolves_percentages() {
+        let quotas = |max_size: &str| BucketQuotas {
+            max_size: Some(Quantity(max_size.into())),
+            ..Default::default()
+        };
+
+        assert_eq!(max_size(&BucketQuotas::default(), None), Ok(None));
+        assert_eq!(max_size(&quotas("1Ki"), None), Ok(Some(1024)));
+        assert_eq!(max_size(&quotas("10%"), Some(1000)), Ok(Some(100)));
+        assert_eq!(max_size(&quotas("100%"), Some(1000)), Ok(Some(1000)));
+        assert_eq!(max_size(&quotas("2.5%"), Some(1000)), Ok(Some(25)));
+
+        // Percentages can only be resolved once the garage knows its capacity
+        assert!(max_size(&quotas("10%"), None).is_err());
+        assert!(max_size(&quotas("10%"), Some(0)).is_err());
+
+        for invalid in ["0%", "-5%", "100.5%", "abc%", "%", "NaN%"] {
+            assert!(max_size(&quotas(invalid), Some(1000)).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn removing_website_disables_it() {
+        let garage_config = GarageConfig::default();
+
+        // Enabling the website should use the defaults of the garage
+        let enabled: BucketSpec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+            "website": { "enabled": true },
+        }))
+        .unwrap();
+        let access = website_access(&enabled.website, &garage_config);
+        assert_eq!(access.enabled, Some(true));
+        assert_eq!(access.index_document, Some(garage_config.web_index.clone()));
+
+        // Removing the website block afterwards should explicitly disable it
+        let removed: BucketSpec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+        }))
+        .unwrap();
+        let access = website_access(&removed.website, &garage_config);
+        assert_eq!(access.enabled, Some(false));
+        assert_eq!(access.index_document, None);
+    }
+
+    fn bucket_spec(spec: serde_json::Value) -> BucketSpec {
+        let mut spec = spec;
+        spec["garageRef"] = json!({ "name": "garage", "namespace": "default" });
+        serde_json::from_value(spec).unwrap()
+    }
+
+    #[test]
+    fn removed_website_of_ready_bucket_is_disabled_once() {
+        let admin = admin();
+
+        // Garage still serves the website that was enabled before the block was removed
+        let info: BucketInfo = serde_json::from_value(json!({
+            "id": "abc",
+            "websiteAccess": true,
+            "websiteConfig": { "indexDocument": "index.html" },
+            "quotas": { "maxSize": null, "maxObjects": null },
+        }))
+        .unwrap();
+
+        let update = admin
+            .bucket_drift("bucket", &bucket_spec(json!({})), &info)
+            .unwrap()
+            .expect("the website should be disabled");
+        let access = update.website_access.unwrap();
+        assert_eq!(access.enabled, Some(false));
+        assert_eq!(access.index_document, None);
+
+        // Once applied, nothing is left to update
+        let info: BucketInfo = serde_json::from_value(json!({
+            "id": "abc",
+            "websiteAccess": false,
+            "quotas": { "maxSize": null, "maxObjects": null },
+        }))
+        .unwrap();
+        assert!(admin
+            .bucket_drift("bucket", &bucket_spec(json!({})), &info)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn every_bucket_setting_is_checked_for_drift() {
+        let admin = admin();
+        let spec = bucket_spec(json!({
+            "quotas": { "maxSize": "1Ki", "maxObjectCount": 10 },
+            "website": {
+                "enabled": true,
+                "indexDocument": "home.html",
+                "errorDocument": "404.html",
+            },
+        }));
+        let info = |changes: serde_json::Value| {
+            let mut info = json!({
+                "id": "abc",
+                "websiteAccess": true,
+                "websiteConfig": { "indexDocument": "home.html", "errorDocument": "404.html" },
+                "quotas": { "maxSize": 1024, "maxObjects": 10 },
+            });
+            for (key, value) in changes.as_object().unwrap() {
+                info[key] = value.clone();
+            }
+            serde_json::from_value::<BucketInfo>(info).unwrap()
+        };
+        let drifted = |info: BucketInfo| admin.bucket_drift("bucket", &spec, &info).unwrap();
+
+        assert!(drifted(info(json!({}))).is_none());
+        for changes in [
+            json!({ "quotas": { "maxSize": 2048, "maxObjects": 10 } }),
+            json!({ "quotas": { "maxSize": 1024, "maxObjects": 20 } }),
+            json!({ "quotas": { "maxSize": null, "maxObjects": null } }),
+            json!({ "websiteAccess": false }),
+            json!({ "websiteConfig": { "indexDocument": "index.html", "errorDocument": "404.html" } }),
+            json!({ "websiteConfig": { "indexDocument": "home.html" } }),
+        ] {
+            assert!(drifted(info(changes.clone())).is_some(), "{changes}");
+        }
+    }
+}
@@ -1,10 +1,13 @@
-use std::{env, future::IntoFuture as _};
+use std::{env, future::IntoFuture as _, net::SocketAddr, time::Duration};
 
+use anyhow::Context as _;
 use garage_operator::{
     operator::{self, State},
+    reconcilers::ReconcileConfig,
     telemetry,
 };
-use tokio::net::TcpListener;
+use kube::Client;
+use tokio::{net::TcpListener, sync::watch};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -13,40 +16,112 @@ async fn main() -> anyhow::Result<()> {
     // Grab needed env
     let garage_version =
         env::var("GARAGE_VERSION").expect("missing GARAGE_VERSION environment variable");
+    let reconcile_config = ReconcileConfig::from_env()?;
+
+    // Get a k8s client for communicating with the cluster
+    let client = Client::try_default()
+        .await
+        .context("failed to create kube client")?;
 
     // Initialize Kubernetes controller state
-    let state = State::default();
+    let state = State::default()
+        .with_client(client.clone())
+        .with_garage_version(garage_version.clone())
+        .with_reconcile_config(reconcile_config.clone());
     let controller = operator::GarageController::new(state.clone());
 
+    // Optionally only converge the current state of the cluster once, and then exit
+    let once = env::args().any(|arg| arg == "--once")
+        || env::var("RECONCILE_ONCE").is_ok_and(|once| once == "true");
+    if once {
+        let timeout = env::var("RECONCILE_ONCE_TIMEOUT_SECONDS")
+            .map(|seconds| seconds.parse())
+            .unwrap_or(Ok(300))?;
+
+        let ready = controller
+            .run_once(
+                client,
+                garage_version,
+                reconcile_config,
+                Duration::from_secs(timeout),
+            )
+            .await?;
+        std::process::exit(if ready { 0 } else { 1 });
+    }
+
+    // Shut down both the controller and the web server as soon as either of them stops
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let shutdown = |mut rx: watch::Receiver<()>| async move {
+        let _ = rx.changed().await;
+    };
+
     // Start web server
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    let router = handlers::router();
-    let server = axum::serve(listener, router.with_state(state));
-
-    // Run both the http server and the controller, throwing a panic if either finish early
-    tokio::select! {
-        c = controller.run(garage_version) => {
-            panic!("controller exited early: {}", c.unwrap_err())
+    let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
+    let listener = TcpListener::bind(&bind_addr)
+        .await
+        .with_context(|| format!("could not bind the web server to {bind_addr}"))?;
+    let debug_config = env::var("DEBUG_CONFIG_ENDPOINT").is_ok_and(|debug| debug == "true");
+    let router = handlers::router(debug_config)
+        .with_state(state)
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let server =
+        axum::serve(listener, router).with_graceful_shutdown(shutdown(shutdown_rx.clone()));
+
+    // Run both the http server and the controller until they have both drained
+    let (c, s) = tokio::join!(
+        async {
+            let c = controller
+                .run(
+                    client,
+                    garage_version,
+                    reconcile_config,
+                    shutdown(shutdown_rx),
+                )
+                .await;
+            let _ = shutdown_tx.send(());
+            c
         },
-        s = server.into_future() => {
-            panic!("server exited early: {}", s.unwrap_err())
-        }
-    };
+        async {
+            let s = server.into_future().await;
+            let _ = shutdown_tx.send(());
+            s
+        },
+    );
+    c.context("controller exited with an error")?;
+    s.context("server exited with an error")?;
+
+    Ok(())
 }
 
 /// Handlers for the web server portion of the operator
 mod handlers {
-    use axum::{extract::State, http::StatusCode, response::IntoResponse, routing, Json, Router};
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::{ConnectInfo, Path, State},
+        http::StatusCode,
+        response::IntoResponse,
+        routing, Json, Router,
+    };
     use prometheus::{Encoder, TextEncoder};
 
-    use garage_operator::operator::State as OperatorState;
+    use garage_operator::{operator::State as OperatorState, Error};
 
     /// Construct the router for all the handlers
-    pub fn router() -> Router<OperatorState> {
-        Router::new()
+    ///
+    /// The rendered configs of garages are only served if `debug_config` is set.
+    pub fn router(debug_config: bool) -> Router<OperatorState> {
+        let router = Router::new()
             .route("/metrics", routing::get(metrics))
             .route("/health", routing::get(health))
-            .route("/", routing::get(index))
+            .route("/status", routing::get(status))
+            .route("/", routing::get(index));
+
+        if debug_config {
+            router.route("/garages/:namespace/:name/config", routing::get(config))
+        } else {
+            router
+        }
     }
 
     /// Handler for exposing prometheus metrics
@@ -64,6 +139,41 @@ mod handlers {
         (StatusCode::OK, Json("healthy"))
     }
 
+    /// Handler for summarizing the state of all managed resources
+    async fn status(State(state): State<OperatorState>) -> impl IntoResponse {
+        match state.managed_resources().await {
+            Ok(Some(resources)) => (StatusCode::OK, Json(resources)).into_response(),
+            Ok(None) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+
+    /// Handler for rendering the config which the operator would deploy for a garage
+    ///
+    /// The operator can read garages in any namespace, so this is only served to clients on
+    /// the same host, e.g. through `kubectl port-forward`.
+    async fn config(
+        State(state): State<OperatorState>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        Path((namespace, name)): Path<(String, String)>,
+    ) -> impl IntoResponse {
+        if !peer.ip().to_canonical().is_loopback() {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        match state.rendered_config(&namespace, &name).await {
+            Ok(Some(config)) => (StatusCode::OK, config).into_response(),
+            Ok(None) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            Err(Error::KubeError(kube::Error::Api(e))) if e.code == 404 => {
+                (StatusCode::NOT_FOUND, e.message).into_response()
+            }
+            Err(e @ Error::IllegalGarage(..)) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response()
+            }
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        }
+    }
+
     /// Handler for interacting with the operator
     async fn index(State(state): State<OperatorState>) -> impl IntoResponse {
         let diagnostics = state.diagnostics().await;
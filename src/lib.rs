@@ -15,8 +15,15 @@ pub mod telemetry;
 mod metrics;
 pub use metrics::Metrics;
 
+/// The version in the `app.kubernetes.io/version` label of the selectors of garage resources
+///
+/// Selectors of deployments can't be changed, so this stays fixed while the version actually
+/// run by each garage is labelled separately.
 pub const GARAGE_VERSION: &str = "0.8.2";
 
+/// Conversions of kubernetes quantities
+mod quantity;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("SerializationError: {0}")]
@@ -39,6 +46,12 @@ pub enum Error {
     #[error("invalid configuration for garage '{0}': {1}")]
     IllegalGarage(String, String),
 
+    #[error("referenced garage does not exist: {0}")]
+    MissingGarage(String),
+
+    #[error("referenced bucket does not exist: {0}")]
+    MissingBucket(String),
+
     #[error("specified source does not exist: {0}")]
     MissingDataSource(String),
 
@@ -49,7 +62,14 @@ pub enum Error {
     MissingSecretData(String),
 
     #[error("Network error: {0}")]
-    NetworkError(#[from] progenitor_client::Error),
+    // NB: boxed as the admin API error is much larger than the others
+    NetworkError(#[source] Box<progenitor_client::Error>),
+}
+
+impl From<progenitor_client::Error> for Error {
+    fn from(e: progenitor_client::Error) -> Self {
+        Error::NetworkError(Box::new(e))
+    }
 }
 
 /// Alias for the common error type
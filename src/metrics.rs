@@ -1,13 +1,22 @@
 use crate::{resources::Garage, Error};
 use kube::ResourceExt;
-use prometheus::{histogram_opts, opts, HistogramVec, IntCounter, IntCounterVec, Registry};
+use prometheus::{histogram_opts, opts, HistogramVec, IntCounterVec, IntGaugeVec, Registry};
 use tokio::time::Instant;
 
+/// Labels identifying the owning garage instance of a metric.
+///
+/// Only the owning garage is used, so that the cardinality of the metrics stays bounded by
+/// the number of garage instances rather than the number of buckets or keys.
+const INSTANCE_LABELS: [&str; 2] = ["namespace", "instance"];
+
 #[derive(Clone)]
 pub struct Metrics {
-    pub reconciliations: IntCounter,
+    pub reconciliations: IntCounterVec,
     pub failures: IntCounterVec,
     pub reconcile_duration: HistogramVec,
+    pub patches: IntCounterVec,
+    pub admin_reachable: IntGaugeVec,
+    pub admin_requests: IntCounterVec,
 }
 
 impl Default for Metrics {
@@ -18,7 +27,7 @@ impl Default for Metrics {
                 "The duration of reconcile to complete in seconds"
             )
             .buckets(vec![0.01, 0.1, 0.25, 0.5, 1., 5., 15., 60.]),
-            &[],
+            &INSTANCE_LABELS,
         )
         .unwrap();
         let failures = IntCounterVec::new(
@@ -26,15 +35,45 @@ impl Default for Metrics {
                 "garage_operator_reconciliation_errors_total",
                 "reconciliation errors",
             ),
-            &["instance", "error"],
+            &["namespace", "instance", "error"],
+        )
+        .unwrap();
+        let reconciliations = IntCounterVec::new(
+            opts!("garage_operator_reconciliations_total", "reconciliations"),
+            &INSTANCE_LABELS,
+        )
+        .unwrap();
+        let patches = IntCounterVec::new(
+            opts!(
+                "garage_operator_patches_total",
+                "patches of managed resources, by whether they were applied or skipped as unchanged",
+            ),
+            &["kind", "result"],
+        )
+        .unwrap();
+        let admin_reachable = IntGaugeVec::new(
+            opts!(
+                "garage_admin_reachable",
+                "whether the last request to the admin API of a garage succeeded",
+            ),
+            &["namespace", "name"],
+        )
+        .unwrap();
+        let admin_requests = IntCounterVec::new(
+            opts!(
+                "garage_admin_requests_total",
+                "requests made to the admin API of a garage (as namespace/name), by method",
+            ),
+            &["method", "garage"],
         )
         .unwrap();
-        let reconciliations =
-            IntCounter::new("garage_operator_reconciliations_total", "reconciliations").unwrap();
         Metrics {
             reconciliations,
             failures,
             reconcile_duration,
+            patches,
+            admin_reachable,
+            admin_requests,
         }
     }
 }
@@ -45,20 +84,61 @@ impl Metrics {
         registry.register(Box::new(self.reconcile_duration.clone()))?;
         registry.register(Box::new(self.failures.clone()))?;
         registry.register(Box::new(self.reconciliations.clone()))?;
+        registry.register(Box::new(self.patches.clone()))?;
+        registry.register(Box::new(self.admin_reachable.clone()))?;
+        registry.register(Box::new(self.admin_requests.clone()))?;
         Ok(self)
     }
 
     pub fn reconcile_failure(&self, garage: &Garage, e: &Error) {
+        let [namespace, instance] = instance_labels(garage);
         self.failures
-            .with_label_values(&[garage.name_any().as_ref(), e.metric_label().as_ref()])
+            .with_label_values(&[&namespace, &instance, e.metric_label().as_ref()])
+            .inc()
+    }
+
+    pub fn patch_applied(&self, kind: &str) {
+        self.patches.with_label_values(&[kind, "applied"]).inc()
+    }
+
+    pub fn patch_skipped(&self, kind: &str) {
+        self.patches.with_label_values(&[kind, "skipped"]).inc()
+    }
+
+    /// Record whether the admin API of a garage could be reached, or forget it if it is not
+    /// expected to be reachable at all
+    pub fn admin_reachable(&self, garage: &Garage, reachable: Option<bool>) {
+        let [namespace, instance] = instance_labels(garage);
+        match reachable {
+            Some(reachable) => self
+                .admin_reachable
+                .with_label_values(&[&namespace, &instance])
+                .set(i64::from(reachable)),
+            None => {
+                let _ = self
+                    .admin_reachable
+                    .remove_label_values(&[&namespace, &instance]);
+            }
+        }
+    }
+
+    /// Count a request made to the admin API of a garage
+    pub fn admin_request(&self, garage: &Garage, method: &str) {
+        let [namespace, name] = instance_labels(garage);
+        self.admin_requests
+            .with_label_values(&[method, &format!("{namespace}/{name}")])
             .inc()
     }
 
-    pub fn count_and_measure(&self) -> ReconcileMeasurer {
-        self.reconciliations.inc();
+    pub fn count_and_measure(&self, garage: &Garage) -> ReconcileMeasurer {
+        let labels = instance_labels(garage);
+        self.reconciliations
+            .with_label_values(&[&labels[0], &labels[1]])
+            .inc();
         ReconcileMeasurer {
             start: Instant::now(),
             metric: self.reconcile_duration.clone(),
+            labels,
         }
     }
 }
@@ -69,12 +149,20 @@ impl Metrics {
 pub struct ReconcileMeasurer {
     start: Instant,
     metric: HistogramVec,
+    labels: [String; 2],
 }
 
 impl Drop for ReconcileMeasurer {
     fn drop(&mut self) {
         #[allow(clippy::cast_precision_loss)]
         let duration = self.start.elapsed().as_millis() as f64 / 1000.0;
-        self.metric.with_label_values(&[]).observe(duration);
+        self.metric
+            .with_label_values(&[&self.labels[0], &self.labels[1]])
+            .observe(duration);
     }
 }
+
+/// Values for [INSTANCE_LABELS] of a garage
+fn instance_labels(garage: &Garage) -> [String; 2] {
+    [garage.namespace().unwrap_or_default(), garage.name_any()]
+}
@@ -1,7 +1,8 @@
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, sync::Arc, time::Duration};
 
 use chrono::{DateTime, Utc};
 use futures::StreamExt;
+use k8s_openapi::api::apps::v1::Deployment;
 use kube::{
     api::ListParams,
     core::object::HasSpec,
@@ -16,12 +17,17 @@ use kube::{
     Api, Client, Resource, ResourceExt,
 };
 use serde::Serialize;
-use tokio::sync::RwLock;
-use tracing::{error, field, info, instrument, Span};
+use tokio::{
+    sync::RwLock,
+    time::{sleep, Instant},
+};
+use tracing::{error, field, info, instrument, warn, Span};
 
 use crate::{
-    reconcilers::{CommonContext as Context, Reconcile},
-    resources::{AccessKey, Bucket, Garage},
+    reconcilers::{cleanup_expired, CommonContext as Context, Reconcile, ReconcileConfig},
+    resources::{
+        AccessKey, AccessKeyState, Bucket, BucketState, Garage, GarageState, NamespacedReference,
+    },
     telemetry, Error, Metrics, Result,
 };
 
@@ -44,11 +50,35 @@ impl Default for Diagnostics {
     }
 }
 impl Diagnostics {
-    pub fn recorder(&self, client: Client, garage: &Garage) -> Recorder {
-        Recorder::new(client, self.reporter.clone(), garage.object_ref(&()))
+    pub fn recorder<K: Resource<DynamicType = ()>>(
+        &self,
+        client: Client,
+        resource: &K,
+    ) -> Recorder {
+        Recorder::new(client, self.reporter.clone(), resource.object_ref(&()))
     }
 }
 
+/// The maximum amount of each kind of resource to list when summarizing
+const SUMMARY_LIMIT: u32 = 100;
+
+/// Summary of a single resource managed by the operator
+#[derive(Clone, Serialize)]
+pub struct ManagedResource {
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+    pub state: Option<String>,
+}
+
+/// Summary of all resources managed by the operator
+#[derive(Clone, Default, Serialize)]
+pub struct ManagedResources {
+    pub resources: Vec<ManagedResource>,
+    /// Whether or not some resources were left out to keep the summary bounded
+    pub truncated: bool,
+}
+
 /// State shared between the controller and the web server
 #[derive(Clone, Default)]
 pub struct State {
@@ -56,6 +86,12 @@ pub struct State {
     diagnostics: Arc<RwLock<Diagnostics>>,
     /// Metrics registry
     registry: prometheus::Registry,
+    /// Client used for summarizing the managed resources
+    client: Option<Client>,
+    /// Version of garage which the rendered configs are meant for
+    garage_version: String,
+    /// Tunables of the running controller, used when rendering configs
+    reconcile_config: ReconcileConfig,
 }
 
 /// State wrapper around the controller outputs for the web server
@@ -70,13 +106,93 @@ impl State {
         self.diagnostics.read().await.clone()
     }
 
+    /// Use the specified client for querying the cluster
+    pub fn with_client(self, client: Client) -> Self {
+        Self {
+            client: Some(client),
+            ..self
+        }
+    }
+
+    /// Use the specified version of garage when rendering configs
+    pub fn with_garage_version(self, garage_version: String) -> Self {
+        Self {
+            garage_version,
+            ..self
+        }
+    }
+
+    /// Use the tunables of the running controller when rendering configs
+    pub fn with_reconcile_config(self, reconcile_config: ReconcileConfig) -> Self {
+        Self {
+            reconcile_config,
+            ..self
+        }
+    }
+
+    /// Render the config which the operator would deploy for a garage
+    ///
+    /// Returns `None` if no client was configured.
+    pub async fn rendered_config(&self, namespace: &str, name: &str) -> Result<Option<String>> {
+        let Some(client) = self.client.clone() else {
+            return Ok(None);
+        };
+
+        let garage = Api::<Garage>::namespaced(client.clone(), namespace)
+            .get(name)
+            .await?;
+
+        // Rendering must not count towards the metrics of the controller
+        let context = Arc::new(Context {
+            client,
+            metrics: Metrics::default(),
+            diagnostics: self.diagnostics.clone(),
+            garage_version: self.garage_version.clone(),
+            config: self.reconcile_config.clone(),
+            admin_clients: Default::default(),
+        });
+        garage.render_config(context).await.map(Some)
+    }
+
+    /// Summarize the state of all of the resources managed by the operator
+    ///
+    /// Returns `None` if no client was configured.
+    pub async fn managed_resources(&self) -> Result<Option<ManagedResources>> {
+        let Some(client) = self.client.clone() else {
+            return Ok(None);
+        };
+
+        let mut summary = ManagedResources::default();
+        summarize(&mut summary, Api::<Garage>::all(client.clone()), |g| {
+            g.status.as_ref().map(|s| format!("{:?}", s.state))
+        })
+        .await?;
+        summarize(&mut summary, Api::<Bucket>::all(client.clone()), |b| {
+            b.status.as_ref().map(|s| format!("{:?}", s.state))
+        })
+        .await?;
+        summarize(&mut summary, Api::<AccessKey>::all(client), |k| {
+            k.status.as_ref().map(|s| format!("{:?}", s.state))
+        })
+        .await?;
+
+        Ok(Some(summary))
+    }
+
     // Create a Controller Context that can update State
-    pub(crate) fn to_context(&self, client: Client, garage_version: String) -> Arc<Context> {
+    pub(crate) fn to_context(
+        &self,
+        client: Client,
+        garage_version: String,
+        config: ReconcileConfig,
+    ) -> Arc<Context> {
         Arc::new(Context {
             client,
             metrics: Metrics::default().register(&self.registry).unwrap(),
             diagnostics: self.diagnostics.clone(),
             garage_version,
+            config,
+            admin_clients: Default::default(),
         })
     }
 }
@@ -91,7 +207,16 @@ impl GarageController {
     }
 
     /// Initialize the controller and shared state (given the crd is installed)
-    pub async fn run(self, garage_version: String) -> Result<(), anyhow::Error> {
+    ///
+    /// All resources are managed through `client`, which decides the cluster to operate on.
+    /// The controller stops gracefully on SIGTERM / SIGINT, or once `shutdown` resolves.
+    pub async fn run(
+        self,
+        client: Client,
+        garage_version: String,
+        config: ReconcileConfig,
+        shutdown: impl Future<Output = ()> + Send + Sync + 'static,
+    ) -> Result<(), anyhow::Error> {
         // Error handler for failed reconciliations
         fn error_policy(garage: Arc<Garage>, error: &Error, ctx: Arc<Context>) -> Action {
             error!("reconcile failed: {:?}", error);
@@ -99,15 +224,11 @@ impl GarageController {
             Action::requeue(Duration::from_secs(5))
         }
 
-        // Get a k8s client for communicating with the cluster
-        let client = Client::try_default()
-            .await
-            .expect("failed to create kube Client");
-
         // Create fetchers to our CRDs
         let garages = Api::<Garage>::all(client.clone());
         let buckets = Api::<Bucket>::all(client.clone());
         let access_keys = Api::<AccessKey>::all(client.clone());
+        let deployments = Api::<Deployment>::all(client.clone());
 
         // Test that we can actually query for our CRDs (a.k.a. they are installed)
         if let Err(e) = garages.list(&ListParams::default().limit(1)).await {
@@ -117,9 +238,18 @@ impl GarageController {
         }
 
         // Create a new k8s controller for our CRD resources
-        let watching_config = Config::default().page_size(50).any_semantic();
-        Controller::new(garages, watching_config.clone())
+        let watching_config = {
+            let watching_config = Config::default().page_size(config.watch_page_size);
+            if config.watch_any_semantic {
+                watching_config.any_semantic()
+            } else {
+                watching_config
+            }
+        };
+        let controller = Controller::new(garages, watching_config.clone())
             .shutdown_on_signal()
+            .graceful_shutdown_on(shutdown)
+            .owns(deployments, watching_config.clone())
             .watches(buckets, watching_config.clone(), |bucket| {
                 // Kick off reconciliation for the owning garage
                 Some(
@@ -133,11 +263,24 @@ impl GarageController {
                     ObjectRef::new(&access_key.spec().garage_ref.name)
                         .within(&access_key.spec().garage_ref.namespace),
                 )
-            })
+            });
+
+        // Periodically reconcile everything, in case the watches missed a change
+        let controller = match config.resync {
+            Some(period) => {
+                controller.reconcile_all_on(futures::stream::unfold((), move |()| async move {
+                    sleep(period).await;
+                    Some(((), ()))
+                }))
+            }
+            None => controller,
+        };
+
+        controller
             .run(
                 reconcile,
                 error_policy,
-                self.state.to_context(client, garage_version),
+                self.state.to_context(client, garage_version, config),
             )
             .filter_map(|x| async move { Result::ok(x) })
             .for_each(|_| futures::future::ready(()))
@@ -147,6 +290,110 @@ impl GarageController {
     }
 }
 
+impl GarageController {
+    /// Reconcile all existing garages until they and their resources are ready
+    ///
+    /// Returns whether or not everything became ready before the timeout.
+    pub async fn run_once(
+        self,
+        client: Client,
+        garage_version: String,
+        config: ReconcileConfig,
+        timeout: Duration,
+    ) -> Result<bool, anyhow::Error> {
+        let ctx = self
+            .state
+            .to_context(client.clone(), garage_version, config);
+
+        let garages = Api::<Garage>::all(client.clone());
+        let buckets = Api::<Bucket>::all(client.clone());
+        let access_keys = Api::<AccessKey>::all(client);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Find every garage which is not ready, or which owns a resource which is not ready
+            let buckets = buckets.list(&ListParams::default()).await?;
+            let access_keys = access_keys.list(&ListParams::default()).await?;
+            let pending = garages
+                .list(&ListParams::default())
+                .await?
+                .into_iter()
+                .filter(|g| {
+                    let owns = |garage_ref: &NamespacedReference| {
+                        garage_ref.name == g.name_any()
+                            && Some(&garage_ref.namespace) == g.namespace().as_ref()
+                    };
+
+                    // Suspended garages are settled once they are reported as such
+                    if g.spec.suspended {
+                        return g.status.as_ref().map(|s| &s.state)
+                            != Some(&GarageState::Suspended);
+                    }
+
+                    g.status.as_ref().map(|s| &s.state) != Some(&GarageState::Ready)
+                        || buckets.iter().any(|b| {
+                            owns(&b.spec.garage_ref)
+                                && b.status.as_ref().map(|s| &s.state) != Some(&BucketState::Ready)
+                        })
+                        || access_keys.iter().any(|k| {
+                            owns(&k.spec.garage_ref)
+                                && k.status.as_ref().map(|s| &s.state)
+                                    != Some(&AccessKeyState::Ready)
+                        })
+                })
+                .collect::<Vec<_>>();
+
+            if pending.is_empty() {
+                info!("All garages are ready");
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                error!(
+                    "Timed out waiting for {} garage(s) to be ready",
+                    pending.len()
+                );
+                return Ok(false);
+            }
+
+            for garage in pending {
+                let garage = Arc::new(garage);
+                if let Err(e) = reconcile(garage.clone(), ctx.clone()).await {
+                    error!("reconcile failed: {:?}", e);
+                    ctx.metrics.reconcile_failure(&garage, &e);
+                }
+            }
+
+            sleep(Duration::from_secs(2)).await;
+        }
+    }
+}
+
+/// Add a bounded listing of a kind of resource to a summary
+async fn summarize<K>(
+    summary: &mut ManagedResources,
+    api: Api<K>,
+    state: impl Fn(&K) -> Option<String>,
+) -> Result<()>
+where
+    K: Resource<DynamicType = ()> + Clone + std::fmt::Debug + serde::de::DeserializeOwned,
+{
+    let list = api
+        .list(&ListParams::default().limit(SUMMARY_LIMIT))
+        .await?;
+
+    summary.truncated |= list.metadata.continue_.is_some_and(|c| !c.is_empty());
+    summary
+        .resources
+        .extend(list.items.iter().map(|resource| ManagedResource {
+            kind: K::kind(&()).to_string(),
+            namespace: resource.namespace(),
+            name: resource.name_any(),
+            state: state(resource),
+        }));
+
+    Ok(())
+}
+
 /// Main reconciler for all garage operator related resources
 #[instrument(skip(ctx, garage), fields(trace_id))]
 async fn reconcile(garage: Arc<Garage>, ctx: Arc<Context>) -> Result<Action> {
@@ -156,7 +403,14 @@ async fn reconcile(garage: Arc<Garage>, ctx: Arc<Context>) -> Result<Action> {
             .diagnostics
             .read()
             .await
-            .recorder(ctx.client.clone(), &garage);
+            .recorder(ctx.client.clone(), garage.as_ref());
+
+        // Stop reporting on the admin API of a garage which is going away
+        ctx.metrics.admin_reachable(&garage, None);
+        ctx.admin_clients.forget(&garage);
+
+        // Nothing will check on the buckets of this garage anymore, so let go of them
+        garage.release_buckets(ctx.client.clone()).await?;
 
         // Garage doesn't have any real cleanup, so we just publish an event
         recorder
@@ -176,8 +430,8 @@ async fn reconcile(garage: Arc<Garage>, ctx: Arc<Context>) -> Result<Action> {
     let trace_id = telemetry::get_trace_id();
 
     // Take some metrics to see the average reconcile time
-    Span::current().record("trace_id", &field::display(&trace_id));
-    let _timer = ctx.metrics.count_and_measure();
+    Span::current().record("trace_id", field::display(&trace_id));
+    let _timer = ctx.metrics.count_and_measure(&garage);
     ctx.diagnostics.write().await.last_event = Utc::now();
 
     let garages_handle: Api<Garage> =
@@ -191,8 +445,29 @@ async fn reconcile(garage: Arc<Garage>, ctx: Arc<Context>) -> Result<Action> {
     info!(r#"Starting Garage reconciliation for "{namespace}/{name}""#);
     finalizer(&garages_handle, GARAGE_FINALIZER, garage, |event| async {
         match event {
-            Finalizer::Apply(g) => g.reconcile(ctx.clone()).await,
-            Finalizer::Cleanup(g) => cleanup(g, ctx.clone()).await,
+            Finalizer::Apply(g) => {
+                let result = g.reconcile(ctx.clone()).await;
+                if let Err(e) = &result {
+                    if let Err(record_error) = g.record_failure(ctx.clone(), e).await {
+                        warn!("Could not record reconcile failure: {record_error}");
+                    }
+                }
+
+                result
+            }
+            Finalizer::Cleanup(g) => {
+                let deleted_at = g.meta().deletion_timestamp.as_ref().map(|t| t.0);
+                match cleanup(g, ctx.clone()).await {
+                    // Let the garage go rather than keeping it stuck terminating forever
+                    Err(e)
+                        if cleanup_expired(deleted_at, ctx.config.cleanup_timeout, Utc::now()) =>
+                    {
+                        warn!(r#"Giving up on cleaning up garage "{namespace}/{name}": {e}"#);
+                        Ok(Action::await_change())
+                    }
+                    result => result,
+                }
+            }
         }
     })
     .await
@@ -0,0 +1,51 @@
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube_quantity::ParsedQuantity;
+
+/// The amount of bytes in a quantity, e.g. `1073741824` for `1Gi`.
+///
+/// Returns why the quantity is not a valid size otherwise, so that callers can report it
+/// against the resource it was specified for.
+pub(crate) fn quantity_to_bytes(quantity: &Quantity) -> Result<i64, String> {
+    let bytes = ParsedQuantity::try_from(quantity)
+        .map_err(|e| format!("invalid quantity {}: {e}", quantity.0))?
+        .to_bytes_i64()
+        .ok_or_else(|| format!("quantity {} is too large", quantity.0))?;
+    if bytes < 0 {
+        return Err(format!("quantity {} must not be negative", quantity.0));
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_quantities_are_converted() {
+        for (quantity, bytes) in [
+            ("0", 0),
+            ("1024", 1024),
+            ("1Ki", 1024),
+            ("1k", 1000),
+            ("1Gi", 1 << 30),
+            ("1.5Mi", 3 << 19),
+        ] {
+            assert_eq!(
+                quantity_to_bytes(&Quantity(quantity.into())),
+                Ok(bytes),
+                "{quantity}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_quantities_are_rejected() {
+        for quantity in ["", "lots", "-1Gi", "100Ei"] {
+            assert!(
+                quantity_to_bytes(&Quantity(quantity.into())).is_err(),
+                "{quantity}"
+            );
+        }
+    }
+}
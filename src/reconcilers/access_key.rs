@@ -1,15 +1,16 @@
 use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
 use kube::{
-    api::{Patch, PatchParams},
+    api::{DeleteParams, Patch, PatchParams},
     runtime::controller::Action,
-    Api, Resource as _, ResourceExt as _,
+    Api, Client, Resource, ResourceExt as _,
 };
 use serde_json::json;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
+    admin_api::GarageAdmin,
     meta,
     resources::{AccessKey, AccessKeyState, AccessKeyStatus, Bucket, Garage},
     Error,
@@ -17,10 +18,16 @@ use crate::{
 
 use super::{CommonContext, Reconcile};
 
+/// Annotation exposing the garage-internal ID of an access key
+pub const ACCESS_KEY_ID_ANNOTATION: &str = "deuxfleurs.fr/access-key-id";
+
 pub struct AccessKeyContext {
     pub common: Arc<CommonContext>,
     pub owner: Garage,
     pub bucket: Bucket,
+
+    /// Admin API handle of the owning garage, shared for the current reconcile pass
+    pub admin: Arc<GarageAdmin>,
 }
 
 #[async_trait::async_trait]
@@ -37,8 +44,18 @@ impl Reconcile for AccessKey {
             self.spec.bucket_ref.name,
         );
 
+        // Keys can only be linked once their bucket exists in garage, so wait for it
+        let Ok(bucket_id) = context.bucket.garage_id() else {
+            info!(
+                "Waiting for bucket '{}' before reconciling access key '{}'",
+                context.bucket.name_any(),
+                self.name_any(),
+            );
+            return Ok(Action::requeue(Duration::from_secs(2)));
+        };
+
         // Grab a handle to the admin API for querying the running instance
-        let admin = context.owner.create_admin(context.common.clone()).await?;
+        let admin = &context.admin;
 
         // Extract needed info from this bucket
         let name = self.name_any();
@@ -53,7 +70,7 @@ impl Reconcile for AccessKey {
         // Get the last known status of this bucket, using the default if not present
         let status = self.status.clone().unwrap_or_default();
 
-        let (requeue, next_status) = match status.state {
+        let (requeue, mut next_status) = match status.state {
             AccessKeyState::Creating => {
                 // Grab the key's ID from garage
                 let id = if let Some(k) = admin.get_key_by_name(&name, false).await? {
@@ -68,43 +85,93 @@ impl Reconcile for AccessKey {
                     AccessKeyStatus {
                         id,
                         state: AccessKeyState::Configuring,
-                        permissions_friendly: self.spec.permissions.to_string(),
+                        permissions_friendly: self.spec.permissions.effective().to_string(),
+                        requested_permissions: self.spec.permissions.effective().to_string(),
+                        ..Default::default()
                     },
                 )
             }
 
             // Link the access key to the correct bucket and update permissions
             AccessKeyState::Configuring => {
-                admin.allow_key_for_bucket(self, &context.bucket).await?;
+                admin
+                    .set_key_permissions_for_bucket(
+                        self.garage_id()?,
+                        bucket_id,
+                        &self.spec.permissions,
+                    )
+                    .await?;
+
+                // Report the permissions that garage actually applied
+                let granted = match admin
+                    .get_key_permissions_for_bucket(&status.id, bucket_id)
+                    .await
+                {
+                    Ok(permissions) => Some(permissions.to_string()),
+                    Err(e) => {
+                        warn!("Could not read back permissions of access key '{name}': {e}");
+                        None
+                    }
+                };
 
                 (
                     Duration::from_secs(2),
-                    AccessKeyStatus {
-                        id: status.id,
-                        state: AccessKeyState::Ready,
-                        permissions_friendly: status.permissions_friendly,
-                    },
+                    self.configured_status(status.id, granted),
                 )
             }
 
-            // Continually write the secret in case it gets regenerated
-            AccessKeyState::Ready => {
-                self.deploy_resources(context.clone()).await?;
+            // Apply changes to the permissions, only reporting them once garage has them
+            AccessKeyState::Ready if self.permissions_changed(&status) => {
+                info!("Updating permissions of access key '{name}'");
 
                 (
-                    Duration::from_secs(60 * 60),
+                    Duration::from_secs(1),
                     AccessKeyStatus {
-                        id: status.id,
-                        state: AccessKeyState::Ready,
-                        permissions_friendly: status.permissions_friendly,
+                        state: AccessKeyState::Configuring,
+                        ..status
                     },
                 )
             }
 
+            // Continually write the secret in case it gets regenerated
+            AccessKeyState::Ready => match self.deploy_resources(context.clone()).await {
+                // Leave secrets of other access keys alone instead of clobbering their credentials
+                Err(e @ Error::IllegalAccessKey(..)) => {
+                    warn!("{e}");
+
+                    (
+                        Duration::from_secs(15),
+                        AccessKeyStatus {
+                            state: AccessKeyState::Errored,
+                            message: Some(e.to_string()),
+                            ..status
+                        },
+                    )
+                }
+                result => {
+                    result?;
+
+                    (
+                        context.common.config.ready_requeue,
+                        AccessKeyStatus {
+                            id: status.id,
+                            state: AccessKeyState::Ready,
+                            permissions_friendly: status.permissions_friendly,
+                            requested_permissions: status.requested_permissions,
+                            message: status.message,
+                            ..Default::default()
+                        },
+                    )
+                }
+            },
+
             // If we have encountered an error, try to start over in 15 seconds
             AccessKeyState::Errored => (Duration::from_secs(15), AccessKeyStatus::default()),
         };
 
+        // Let tools waiting on this access key know that its latest spec was seen
+        next_status.observed_generation = self.metadata.generation;
+
         // always overwrite status object with what we saw
         let new_status = Patch::Apply(json!({
             "apiVersion": "deuxfleurs.fr/v0alpha",
@@ -116,6 +183,22 @@ impl Reconcile for AccessKey {
             .patch_status(&name, &ps, &new_status)
             .await?;
 
+        // Expose the garage ID as an annotation as well, for tooling which can't read the status
+        if !next_status.id.is_empty()
+            && self.annotations().get(ACCESS_KEY_ID_ANNOTATION) != Some(&next_status.id)
+        {
+            let annotations = Patch::Apply(json!({
+                "apiVersion": "deuxfleurs.fr/v0alpha",
+                "kind": "AccessKey",
+                "metadata": {
+                    "annotations": {
+                        ACCESS_KEY_ID_ANNOTATION: next_status.id,
+                    },
+                },
+            }));
+            access_key_handle.patch(&name, &ps, &annotations).await?;
+        }
+
         Ok(Action::requeue(requeue))
     }
 
@@ -134,20 +217,50 @@ impl Reconcile for AccessKey {
             .clone()
             .unwrap_or(format!("{}.{}.key", name, self.spec.bucket_ref.name));
 
-        let admin = context.owner.create_admin(context.common.clone()).await?;
+        let admin = &context.admin;
         let secrets_handle = Api::<Secret>::namespaced(context.common.client.clone(), &namespace);
+        let configs_handle =
+            Api::<ConfigMap>::namespaced(context.common.client.clone(), &namespace);
+
+        // Remove the secret written before `writeSecret` was turned off
+        if !self.spec.write_secret {
+            if let Some(secret) = secrets_handle.get_opt(&secret_id).await? {
+                if self.owns(&secret) {
+                    secrets_handle
+                        .delete(&secret_id, &DeleteParams::default())
+                        .await?;
+                }
+            }
+        }
+
+        // Credentials managed elsewhere must not end up in kubernetes
+        if !self.spec.write_secret && !self.spec.write_config_map {
+            return Ok(());
+        }
+
+        // Make sure that the secret isn't already in use by another access key
+        if self.spec.write_secret {
+            if let Some(secret) = secrets_handle.get_opt(&secret_id).await? {
+                if let Some(e) = self.secret_conflict(&secret) {
+                    return Err(e);
+                }
+            }
+        }
+        if self.spec.write_config_map {
+            if let Some(config) = configs_handle.get_opt(&secret_id).await? {
+                if let Some(e) = self.secret_conflict(&config) {
+                    return Err(e);
+                }
+            }
+        }
 
         // Fetch the current secret from garage
         let key = admin.get_key_by_name(&name, true).await?.unwrap();
 
-        // Write out the secret to k8s
+        // Split the credentials between the secret and the config map, if requested
         let garage_config = &context.owner.spec.config;
-        let secret = Secret {
-            metadata: meta! {
-                owners: vec![owner.clone()],
-                name: Some(secret_id.clone())
-            },
-            string_data: Some(BTreeMap::from([
+        let (mut config_data, secret_data) = split_credentials(
+            BTreeMap::from([
                 ("AWS_ACCESS_KEY_ID".into(), key.access_key_id.unwrap()),
                 (
                     "AWS_SECRET_ACCESS_KEY".into(),
@@ -156,26 +269,416 @@ impl Reconcile for AccessKey {
                 ("AWS_DEFAULT_REGION".into(), garage_config.region.clone()),
                 (
                     "AWS_ENDPOINT_URL".into(),
-                    format!(
-                        "http://{}.{}.svc.cluster.local:{}",
-                        context.owner.prefixed_name("api"),
-                        context.owner.namespace().unwrap(),
-                        garage_config.ports.s3_api
-                    ),
+                    context.owner.service_url(garage_config.ports.s3_api),
                 ),
-            ])),
+            ]),
+            self.spec.write_config_map,
+        );
 
-            ..Default::default()
+        // Write out the config map to k8s
+        if self.spec.write_config_map {
+            config_data.insert(
+                "BUCKET_NAME".into(),
+                context.bucket.global_alias(&context.owner)?,
+            );
+            let config = ConfigMap {
+                metadata: meta! {
+                    owners: vec![owner.clone()],
+                    name: Some(secret_id.clone())
+                },
+                data: Some(config_data),
+
+                ..Default::default()
+            };
+
+            configs_handle
+                .patch(
+                    &secret_id,
+                    &PatchParams::apply("garage-operator"),
+                    &Patch::Apply(config),
+                )
+                .await?;
+        }
+
+        // Write out the secret to k8s
+        if self.spec.write_secret {
+            let secret = Secret {
+                metadata: meta! {
+                    owners: vec![owner.clone()],
+                    name: Some(secret_id.clone())
+                },
+                string_data: Some(secret_data),
+
+                ..Default::default()
+            };
+
+            secrets_handle
+                .patch(
+                    &secret_id,
+                    &PatchParams::apply("garage-operator"),
+                    &Patch::Apply(secret),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AccessKey {
+    /// The ID of this access key in garage, if it has been created
+    pub(crate) fn garage_id(&self) -> Result<&str, Error> {
+        self.status
+            .as_ref()
+            .map(|s| s.id.as_str())
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| Error::IllegalAccessKey(self.name_any(), "missing ID in status".into()))
+    }
+
+    /// Whether the requested permissions differ from the ones last applied to garage
+    fn permissions_changed(&self, status: &AccessKeyStatus) -> bool {
+        // Keys configured before the requested permissions were recorded only have the
+        // reported ones
+        let requested = if status.requested_permissions.is_empty() {
+            &status.permissions_friendly
+        } else {
+            &status.requested_permissions
         };
 
-        secrets_handle
-            .patch(
-                &secret_id,
-                &PatchParams::apply("garage-operator"),
-                &Patch::Apply(secret),
-            )
+        self.spec.permissions.effective().to_string() != *requested
+    }
+
+    /// The status of this key once its permissions were applied, given the ones that garage
+    /// reports, if they could be read back
+    ///
+    /// Garage reporting different permissions than requested is surfaced in the message rather
+    /// than applying them again, which would never settle.
+    fn configured_status(&self, id: String, granted: Option<String>) -> AccessKeyStatus {
+        let requested = self.spec.permissions.effective().to_string();
+        let permissions_friendly = granted.unwrap_or_else(|| requested.clone());
+        let message = (permissions_friendly != requested).then(|| {
+            format!("garage reports permissions {permissions_friendly} instead of the requested {requested}")
+        });
+
+        AccessKeyStatus {
+            id,
+            state: AccessKeyState::Ready,
+            permissions_friendly,
+            requested_permissions: requested,
+            message,
+            ..Default::default()
+        }
+    }
+
+    /// The error to report if a secret or config map is already owned by a different access key
+    fn secret_conflict<K: Resource<DynamicType = ()>>(&self, resource: &K) -> Option<Error> {
+        let uid = self.uid();
+        let other = resource
+            .owner_references()
+            .iter()
+            .find(|o| o.kind == "AccessKey" && Some(&o.uid) != uid.as_ref())?;
+
+        Some(Error::IllegalAccessKey(
+            self.name_any(),
+            format!(
+                "{} `{}` is already used by access key `{}`, choose a different secretRef",
+                K::kind(&()),
+                resource.name_any(),
+                other.name,
+            ),
+        ))
+    }
+
+    /// Whether the given resource was written out for this access key
+    fn owns<K: Resource>(&self, resource: &K) -> bool {
+        let uid = self.uid();
+        resource
+            .owner_references()
+            .iter()
+            .any(|o| o.kind == "AccessKey" && Some(&o.uid) == uid.as_ref())
+    }
+
+    /// Whether this access key references the given bucket
+    pub(crate) fn references(&self, bucket: &Bucket) -> bool {
+        let bucket_ref = &self.spec.bucket_ref;
+
+        bucket.name_any() == bucket_ref.name
+            && bucket.namespace().as_ref() == Some(&bucket_ref.namespace)
+    }
+
+    /// Whether the bucket referenced by this access key is missing from the given buckets
+    pub(crate) fn bucket_missing(&self, buckets: &[Bucket]) -> bool {
+        !buckets.iter().any(|b| self.references(b))
+    }
+
+    /// Revoke all access of this key and mark it as errored due to its bucket not existing
+    ///
+    /// Nothing reconciles a key without its bucket, so this is the only chance to take away
+    /// the permissions it was granted on the deleted bucket.
+    pub(crate) async fn report_missing_bucket(
+        &self,
+        client: Client,
+        admin: &GarageAdmin,
+    ) -> Result<(), Error> {
+        let bucket_ref = &self.spec.bucket_ref;
+        let error = Error::MissingBucket(format!("{}/{}", bucket_ref.namespace, bucket_ref.name));
+
+        // Revoking is a no-op for keys without any access left, so just do it on every pass
+        if let Ok(id) = self.garage_id() {
+            info!(
+                "Revoking access of key '{}' as its bucket no longer exists",
+                self.name_any()
+            );
+            admin.revoke_key(id).await?;
+        }
+
+        self.report_error(client, error).await
+    }
+
+    /// Record a failed reconcile on the status of this key, rather than failing the reconcile
+    /// of its bucket
+    pub(crate) async fn record_failure(&self, client: Client, error: Error) {
+        let name = self.name_any();
+        warn!("Could not reconcile access key '{name}': {error}");
+        if let Err(e) = self.report_error(client, error).await {
+            warn!("Could not report failure of access key '{name}': {e}");
+        }
+    }
+
+    /// Mark this access key as errored, letting the user know why
+    async fn report_error(&self, client: Client, error: Error) -> Result<(), Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalAccessKey(name.clone(), "missing namespace".into()))?;
+
+        let status = AccessKeyStatus {
+            state: AccessKeyState::Errored,
+            message: Some(error.to_string()),
+            ..self.status.clone().unwrap_or_default()
+        };
+        let new_status = Patch::Apply(json!({
+            "apiVersion": "deuxfleurs.fr/v0alpha",
+            "kind": "AccessKey",
+            "status": status,
+        }));
+        let ps = PatchParams::apply("garage-operator").force();
+        Api::<AccessKey>::namespaced(client, &namespace)
+            .patch_status(&name, &ps, &new_status)
             .await?;
 
         Ok(())
     }
 }
+
+/// Split the credentials of an access key into those for its config map and its secret
+///
+/// Everything is kept in the secret unless the non-sensitive parts are written separately.
+fn split_credentials(
+    credentials: BTreeMap<String, String>,
+    separate: bool,
+) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    if !separate {
+        return (BTreeMap::new(), credentials);
+    }
+
+    credentials
+        .into_iter()
+        .partition(|(key, _)| key != "AWS_SECRET_ACCESS_KEY")
+}
+
+#[cfg(test)]
+mod test {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+
+    use super::*;
+
+    fn access_key(status: Option<AccessKeyStatus>) -> AccessKey {
+        let spec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+            "bucketRef": { "name": "bucket", "namespace": "default" },
+            "permissions": {},
+            "secretRef": { "name": "secret" },
+        }))
+        .unwrap();
+
+        AccessKey {
+            status,
+            ..AccessKey::new("test", spec)
+        }
+    }
+
+    #[test]
+    fn missing_id_is_an_error() {
+        assert!(matches!(
+            access_key(None).garage_id(),
+            Err(Error::IllegalAccessKey(name, _)) if name == "test"
+        ));
+        assert!(access_key(Some(AccessKeyStatus::default()))
+            .garage_id()
+            .is_err());
+    }
+
+    #[test]
+    fn toggling_permissions_of_ready_key_reconfigures_it() {
+        let mut key = access_key(None);
+        let status = AccessKeyStatus {
+            id: "abc".into(),
+            state: AccessKeyState::Ready,
+            permissions_friendly: key.spec.permissions.effective().to_string(),
+            ..Default::default()
+        };
+        assert!(!key.permissions_changed(&status));
+
+        key.spec.permissions.read = true;
+        assert!(key.permissions_changed(&status));
+
+        // Once applied, the key is settled again
+        let status = AccessKeyStatus {
+            permissions_friendly: "R--".into(),
+            ..status
+        };
+        assert!(!key.permissions_changed(&status));
+
+        key.spec.permissions.read = false;
+        assert!(key.permissions_changed(&status));
+    }
+
+    #[test]
+    fn differing_readback_is_reported_without_reapplying() {
+        let mut key = access_key(None);
+        key.spec.permissions.read = true;
+        key.spec.permissions.write = true;
+
+        // Garage granting exactly what was requested settles the key
+        let status = key.configured_status("abc".into(), Some("RW-".into()));
+        assert_eq!(status.message, None);
+        assert!(!key.permissions_changed(&status));
+
+        // Permissions granted outside of the operator are reported, but not fought over
+        let status = key.configured_status("abc".into(), Some("RWO".into()));
+        assert_eq!(status.state, AccessKeyState::Ready);
+        assert_eq!(status.permissions_friendly, "RWO");
+        assert_eq!(status.requested_permissions, "RW-");
+        assert!(status.message.is_some_and(|m| m.contains("RWO")));
+        assert!(!key.permissions_changed(&status));
+
+        // Changes to the spec are still picked up
+        key.spec.permissions.owner = true;
+        assert!(key.permissions_changed(&status));
+
+        // Without a readback, the requested permissions are reported
+        let status = key.configured_status("abc".into(), None);
+        assert_eq!(status.permissions_friendly, "RWO");
+        assert_eq!(status.message, None);
+    }
+
+    #[test]
+    fn secrets_of_other_access_keys_conflict() {
+        let mut key = access_key(None);
+        key.metadata.uid = Some("mine".into());
+
+        let secret_owned_by = |kind: &str, uid: &str| Secret {
+            metadata: ObjectMeta {
+                name: Some("secret".into()),
+                owner_references: Some(vec![OwnerReference {
+                    api_version: "deuxfleurs.fr/v0alpha".into(),
+                    kind: kind.into(),
+                    name: "other".into(),
+                    uid: uid.into(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(key.secret_conflict(&Secret::default()).is_none());
+        assert!(key
+            .secret_conflict(&secret_owned_by("AccessKey", "mine"))
+            .is_none());
+        assert!(key
+            .secret_conflict(&secret_owned_by("Bucket", "theirs"))
+            .is_none());
+        assert!(matches!(
+            key.secret_conflict(&secret_owned_by("AccessKey", "theirs")),
+            Some(Error::IllegalAccessKey(name, _)) if name == "test"
+        ));
+
+        // Only secrets written for this key are removed once they are no longer wanted
+        assert!(key.owns(&secret_owned_by("AccessKey", "mine")));
+        assert!(!key.owns(&secret_owned_by("AccessKey", "theirs")));
+        assert!(!key.owns(&secret_owned_by("Bucket", "mine")));
+        assert!(!key.owns(&Secret::default()));
+    }
+
+    #[test]
+    fn only_the_secret_key_is_kept_secret() {
+        let credentials = BTreeMap::from([
+            ("AWS_ACCESS_KEY_ID".to_string(), "GK123".to_string()),
+            ("AWS_SECRET_ACCESS_KEY".to_string(), "hunter2".to_string()),
+            ("AWS_DEFAULT_REGION".to_string(), "garage".to_string()),
+        ]);
+
+        let (config, secret) = split_credentials(credentials.clone(), false);
+        assert!(config.is_empty());
+        assert_eq!(secret, credentials);
+
+        let (config, secret) = split_credentials(credentials, true);
+        assert_eq!(
+            config.keys().collect::<Vec<_>>(),
+            ["AWS_ACCESS_KEY_ID", "AWS_DEFAULT_REGION"]
+        );
+        assert_eq!(secret.keys().collect::<Vec<_>>(), ["AWS_SECRET_ACCESS_KEY"]);
+    }
+
+    fn bucket(namespace: &str, name: &str) -> Bucket {
+        let spec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+        }))
+        .unwrap();
+
+        let mut bucket = Bucket::new(name, spec);
+        bucket.metadata.namespace = Some(namespace.into());
+        bucket
+    }
+
+    #[test]
+    fn deleted_buckets_are_missing() {
+        let key = access_key(None);
+        assert!(!key.bucket_missing(&[bucket("other", "bucket"), bucket("default", "bucket")]));
+
+        // The bucket is gone, leaving only unrelated ones behind
+        assert!(key.bucket_missing(&[bucket("other", "bucket"), bucket("default", "other")]));
+        assert!(key.bucket_missing(&[]));
+    }
+
+    #[test]
+    fn keys_recover_once_their_bucket_is_back() {
+        let key = access_key(Some(AccessKeyStatus {
+            id: "abc".into(),
+            state: AccessKeyState::Errored,
+            message: Some(Error::MissingBucket("default/bucket".into()).to_string()),
+            ..Default::default()
+        }));
+
+        // While the bucket is gone, only its garage looks at the key to revoke its access
+        assert!(key.bucket_missing(&[bucket("default", "other")]));
+
+        // Once recreated, the bucket takes the key back and reconciles it out of its error
+        let recreated = bucket("default", "bucket");
+        assert!(!key.bucket_missing(&[recreated.clone()]));
+        assert!(key.references(&recreated));
+        assert!(!key.references(&bucket("other", "bucket")));
+    }
+
+    #[test]
+    fn id_is_read_from_status() {
+        let status = AccessKeyStatus {
+            id: "abc".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(access_key(Some(status)).garage_id().unwrap(), "abc");
+    }
+}
@@ -1,24 +1,48 @@
 use std::{sync::Arc, time::Duration};
 
+use chrono::Utc;
+
 use kube::{
     api::{ListParams, Patch, PatchParams},
-    runtime::controller::Action,
-    Api, ResourceExt as _,
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+    },
+    Api, Client, ResourceExt as _,
 };
 use serde_json::json;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
+    admin_api::{bucket_id, GarageAdmin},
     reconcilers::access_key::AccessKeyContext,
     resources::{AccessKey, Bucket, BucketState, BucketStatus, Garage},
     Error,
 };
 
-use super::{CommonContext, Reconcile};
+use super::{cleanup_expired, CommonContext, Reconcile};
+
+/// Annotation exposing the garage-internal ID of a bucket
+pub const BUCKET_ID_ANNOTATION: &str = "deuxfleurs.fr/bucket-id";
+
+/// Annotation which, when set to `"true"`, prevents a bucket from being deleted
+pub const DELETION_PROTECTION_ANNOTATION: &str = "deuxfleurs.fr/deletion-protection";
+
+/// Annotation which, when set to `"true"`, allows deleting a bucket still used by access keys
+pub const FORCE_DELETE_ANNOTATION: &str = "deuxfleurs.fr/force-delete";
+
+/// Finalizer holding on to buckets with deletion protection enabled or access keys using them
+pub const DELETION_PROTECTION_FINALIZER: &str = "bucket.deuxfleurs.fr/deletion-protection";
 
 pub struct BucketContext {
     pub common: Arc<CommonContext>,
     pub owner: Garage,
+
+    /// Admin API handle of the owning garage, shared for the current reconcile pass
+    pub admin: Arc<GarageAdmin>,
+
+    /// Access keys of the owning garage, listed once for the current reconcile pass
+    pub access_keys: Vec<AccessKey>,
 }
 
 #[async_trait::async_trait]
@@ -33,18 +57,61 @@ impl Reconcile for Bucket {
             self.spec.garage_ref.name,
         );
 
+        // Keep protected buckets around until their protection is removed
+        let blocked = self.sync_deletion_protection(context.clone()).await?;
+        if blocked {
+            return Ok(Action::requeue(context.common.config.ready_requeue));
+        }
+
+        // Buckets can't be moved between garages, so leave it be if its garage was changed
+        if let Some(e) = self.rebind_error() {
+            warn!("{e}");
+            let message = e.to_string();
+            let reported = self
+                .status
+                .as_ref()
+                .is_some_and(|s| s.message.as_ref() == Some(&message));
+            if !reported {
+                let recorder = context
+                    .common
+                    .diagnostics
+                    .read()
+                    .await
+                    .recorder(context.common.client.clone(), self);
+                recorder
+                    .publish(Event {
+                        type_: EventType::Warning,
+                        reason: "GarageRefChanged".into(),
+                        note: Some(message),
+                        action: "Reconciling".into(),
+                        secondary: None,
+                    })
+                    .await?;
+            }
+
+            self.report_error(context.common.client.clone(), e).await?;
+            return Ok(Action::requeue(Duration::from_secs(15)));
+        }
+
         // Grab a handle to the admin API for querying the running instance
-        let admin = context.owner.create_admin(context.common.clone()).await?;
+        let admin = &context.admin;
+
+        // Refuse to pretend that options unsupported by this garage were applied
+        if let Err(e) = admin.check_bucket_support(&self.name_any(), &self.spec) {
+            warn!("{e}");
+            self.report_error(context.common.client.clone(), e).await?;
+            return Ok(Action::requeue(Duration::from_secs(15)));
+        }
 
         // Extract needed info from this bucket
         let name = self.name_any();
         let namespace = self
             .namespace()
             .ok_or_else(|| Error::IllegalBucket(name.clone(), "missing namespace".into()))?;
+        let alias = self.global_alias(&context.owner)?;
 
         // Grab a handle to k8s resources
         let bucket_handle = Api::<Bucket>::namespaced(context.common.client.clone(), &namespace);
-        let access_key_handle = Api::<AccessKey>::all(context.common.client.clone());
 
         // Get the last known status of this bucket, using the default if not present
         let status = self.status.clone().unwrap_or_default();
@@ -53,15 +120,52 @@ impl Reconcile for Bucket {
         self.deploy_resources(context.clone()).await?;
 
         // Handle all possible states for this bucket
-        let (requeue, next_status): (Duration, BucketStatus) = match status.state {
+        let (requeue, mut next_status): (Duration, BucketStatus) = match status.state {
             // The bucket needs to be either created or linked up with an existing bucket
             BucketState::Creating => {
                 // Grab the bucket's ID from garage
-                let id = if let Some(b) = admin.get_bucket_by_name(&name).await? {
-                    b.id.unwrap()
+                let (id, adopted) = if let Some(b) = admin.get_bucket_by_name(&alias).await? {
+                    let id = bucket_id(&alias, b)?;
+
+                    // Two resources managing the same bucket would fight over its config
+                    let buckets = Api::<Bucket>::all(context.common.client.clone())
+                        .list(&ListParams::default())
+                        .await?;
+                    if let Some(e) = self.adoption_conflict(&id, &buckets.items) {
+                        warn!("{e}");
+                        self.report_error(context.common.client.clone(), e).await?;
+                        return Ok(Action::requeue(Duration::from_secs(15)));
+                    }
+
+                    // Retrying after an error finds the bucket this resource created itself
+                    if !self.adopts(&id) {
+                        (id, status.adopted)
+                    } else {
+                        info!("Adopting existing bucket '{alias}' with ID '{id}'");
+                        let recorder = context
+                            .common
+                            .diagnostics
+                            .read()
+                            .await
+                            .recorder(context.common.client.clone(), self);
+                        recorder
+                            .publish(Event {
+                                type_: EventType::Normal,
+                                reason: "Adopted".into(),
+                                note: Some(format!(
+                                    "Adopted existing bucket `{alias}`, its quotas and website \
+                                     access will be replaced by this resource"
+                                )),
+                                action: "Creating".into(),
+                                secondary: None,
+                            })
+                            .await?;
+
+                        (id, true)
+                    }
                 } else {
                     // The bucket doesn't already exist, so create it now
-                    admin.create_bucket(&name).await?.id.unwrap()
+                    (admin.create_bucket(&alias).await?, false)
                 };
 
                 // Save the ID and get ready to configure
@@ -70,64 +174,151 @@ impl Reconcile for Bucket {
                     BucketStatus {
                         id,
                         state: BucketState::Configuring,
+                        adopted,
+                        ..Default::default()
                     },
                 )
             }
 
-            // Apply quotas to our bucket
+            // Apply quotas and website access to our bucket
             BucketState::Configuring => {
                 // Always overwrite with our source of truth
-                admin
-                    .set_bucket_quotas(&status.id, &self.spec.quotas)
-                    .await?;
+                let update = admin.bucket_update(&name, &self.spec)?;
+                admin.apply_bucket_config(&status.id, &update).await?;
 
                 (
                     Duration::from_secs(1),
                     BucketStatus {
                         id: status.id,
                         state: BucketState::Ready,
+                        adopted: status.adopted,
+                        ..Default::default()
                     },
                 )
             }
 
-            // Apply all access keys once we are ready
+            // Apply all access keys once we are ready, since they need the ID of this bucket
             BucketState::Ready => {
-                // Get all buckets that we own and reconcile them
+                // Get all keys that we own and reconcile them
                 // TODO: Should we do this in parallel?
-                // TODO: Listing requires filtering until `selectableFields` is stabilised and added to k8s (v1.30 and beyond)
-                let owned_keys = access_key_handle
-                    .list(&ListParams::default())
-                    .await?
-                    .into_iter()
-                    .filter(|k| {
-                        k.spec.garage_ref.name == context.owner.name_any()
-                            && k.spec.garage_ref.namespace == context.owner.namespace().unwrap()
-                            && k.spec.bucket_ref.name == name
-                            && k.spec.bucket_ref.namespace == namespace
-                    });
+                let owned_keys = context
+                    .access_keys
+                    .iter()
+                    .filter(|k| k.references(self))
+                    .cloned()
+                    .collect::<Vec<_>>();
 
                 let access_key_context = Arc::new(AccessKeyContext {
                     common: context.common.clone(),
                     owner: context.owner.clone(),
                     bucket: self.clone(),
+                    admin: context.admin.clone(),
                 });
-                for access_key in owned_keys {
-                    access_key.reconcile(access_key_context.clone()).await?;
+                for access_key in &owned_keys {
+                    // A broken key should not count as a failure of its bucket or garage
+                    if let Err(e) = access_key.reconcile(access_key_context.clone()).await {
+                        access_key
+                            .record_failure(context.common.client.clone(), e)
+                            .await;
+                    }
+                }
+
+                // Fetch the current state of the bucket from garage
+                let info = match admin.get_bucket_by_id(&status.id).await {
+                    Ok(info) => Some(info),
+                    Err(e) => {
+                        warn!("Could not fetch info of bucket '{name}': {e}");
+                        None
+                    }
+                };
+
+                // Quotas and website access must converge with the spec, including when the
+                // website was removed or the garage capacity that size quotas follow changed
+                let update = match &info {
+                    Some(info) => admin.bucket_drift(&name, &self.spec, info)?,
+                    None => None,
+                };
+                if let Some(update) = update {
+                    info!("Updating configuration of bucket '{name}'");
+                    admin.apply_bucket_config(&status.id, &update).await?;
+                }
+
+                // Check the usage of the bucket against its quotas, keeping the last known
+                // state if garage could not tell us
+                let quota_state = info
+                    .as_ref()
+                    .map(|info| {
+                        self.spec.quotas.usage_state(
+                            info.quotas.as_ref().and_then(|q| q.max_size),
+                            info.bytes.unwrap_or_default(),
+                            info.objects.unwrap_or_default(),
+                        )
+                    })
+                    .or(status.quota_state);
+
+                // Revoke access for any keys that we don't manage, if requested
+                if let Some(info) = info.filter(|_| self.spec.enforce_key_allowlist) {
+                    let unmanaged_keys = info
+                        .keys
+                        .into_iter()
+                        .filter_map(|k| k.access_key_id)
+                        .filter(|id| {
+                            !owned_keys
+                                .iter()
+                                .any(|k| k.status.as_ref().is_some_and(|s| &s.id == id))
+                        });
+
+                    let recorder = context
+                        .common
+                        .diagnostics
+                        .read()
+                        .await
+                        .recorder(context.common.client.clone(), self);
+                    for key_id in unmanaged_keys {
+                        info!("Revoking unmanaged key '{key_id}' from bucket '{name}'");
+                        admin.deny_key_for_bucket(&key_id, &status.id).await?;
+
+                        recorder
+                            .publish(Event {
+                                type_: EventType::Warning,
+                                reason: "UnmanagedKeyRevoked".into(),
+                                note: Some(format!("Revoked access for unmanaged key `{key_id}`")),
+                                action: "Revoking".into(),
+                                secondary: None,
+                            })
+                            .await?;
+                    }
                 }
 
                 (
-                    Duration::from_secs(60 * 60),
+                    context.common.config.ready_requeue,
                     BucketStatus {
                         id: status.id,
                         state: BucketState::Ready,
+                        adopted: status.adopted,
+                        quota_state,
+                        ..Default::default()
                     },
                 )
             }
 
-            // If we have encountered an error, try to start over in 15 seconds
-            BucketState::Errored => (Duration::from_secs(15), BucketStatus::default()),
+            // If we have encountered an error, try to start over in 15 seconds, remembering which
+            // bucket was managed so far
+            BucketState::Errored => (
+                Duration::from_secs(15),
+                BucketStatus {
+                    id: status.id,
+                    adopted: status.adopted,
+                    ..Default::default()
+                },
+            ),
         };
 
+        // Remember which garage and spec this bucket was reconciled against, so that changes
+        // to the garage can be caught and tools can tell that the latest spec was seen
+        next_status.garage_ref = Some(self.spec.garage_ref.clone());
+        next_status.observed_generation = self.metadata.generation;
+
         let new_status = Patch::Apply(json!({
             "apiVersion": "deuxfleurs.fr/v0alpha",
             "kind": "Bucket",
@@ -136,6 +327,22 @@ impl Reconcile for Bucket {
         let ps = PatchParams::apply("garage-operator").force(); // TODO: Why is this force?
         let _o = bucket_handle.patch_status(&name, &ps, &new_status).await?;
 
+        // Expose the garage ID as an annotation as well, for tooling which can't read the status
+        if !next_status.id.is_empty()
+            && self.annotations().get(BUCKET_ID_ANNOTATION) != Some(&next_status.id)
+        {
+            let annotations = Patch::Apply(json!({
+                "apiVersion": "deuxfleurs.fr/v0alpha",
+                "kind": "Bucket",
+                "metadata": {
+                    "annotations": {
+                        BUCKET_ID_ANNOTATION: next_status.id,
+                    },
+                },
+            }));
+            bucket_handle.patch(&name, &ps, &annotations).await?;
+        }
+
         Ok(Action::requeue(requeue))
     }
 
@@ -144,3 +351,489 @@ impl Reconcile for Bucket {
         Ok(())
     }
 }
+
+impl Bucket {
+    /// The ID of this bucket in garage, if it has been created
+    pub(crate) fn garage_id(&self) -> Result<&str, Error> {
+        self.status
+            .as_ref()
+            .map(|s| s.id.as_str())
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| Error::IllegalBucket(self.name_any(), "missing ID in status".into()))
+    }
+
+    /// The global alias of this bucket in garage, following the template of its garage
+    pub(crate) fn global_alias(&self, garage: &Garage) -> Result<String, Error> {
+        let name = self.name_any();
+        let Some(template) = &garage.spec.global_alias_template else {
+            return Ok(name);
+        };
+
+        let alias = template
+            .replace("{namespace}", &self.namespace().unwrap_or_default())
+            .replace("{name}", &name);
+
+        // Garage follows the S3 rules for bucket names
+        let valid = (3..=63).contains(&alias.len())
+            && alias
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+            && !alias.starts_with(['-', '.'])
+            && !alias.ends_with(['-', '.']);
+        if !valid {
+            return Err(Error::IllegalBucket(
+                name,
+                format!(
+                    r#"global alias "{alias}" from template "{template}" is not a valid bucket name"#
+                ),
+            ));
+        }
+
+        Ok(alias)
+    }
+
+    /// The error to report if the garage of this bucket changed since it was last reconciled
+    fn rebind_error(&self) -> Option<Error> {
+        let previous = self.status.as_ref()?.garage_ref.as_ref()?;
+        let current = &self.spec.garage_ref;
+        if previous == current {
+            return None;
+        }
+
+        Some(Error::IllegalBucket(
+            self.name_any(),
+            format!(
+                "garageRef changed from `{}/{}` to `{}/{}`, but buckets cannot be moved between \
+                 garages, delete and recreate the bucket instead",
+                previous.namespace, previous.name, current.namespace, current.name,
+            ),
+        ))
+    }
+
+    /// Whether linking up with the garage bucket with an ID takes over a bucket created elsewhere
+    ///
+    /// Buckets which this resource managed before, such as before an error, were not adopted.
+    fn adopts(&self, id: &str) -> bool {
+        !self.status.as_ref().is_some_and(|s| s.id == id)
+    }
+
+    /// The error to report if another resource already manages the garage bucket with an ID
+    fn adoption_conflict(&self, id: &str, buckets: &[Bucket]) -> Option<Error> {
+        let owner = buckets
+            .iter()
+            .find(|b| b.uid() != self.uid() && b.status.as_ref().is_some_and(|s| s.id == id))?;
+
+        Some(Error::IllegalBucket(
+            self.name_any(),
+            format!(
+                "bucket `{id}` is already managed by `{}/{}`",
+                owner.namespace().unwrap_or_default(),
+                owner.name_any(),
+            ),
+        ))
+    }
+
+    /// Whether deletion protection is enabled for this bucket
+    pub(crate) fn is_deletion_protected(&self) -> bool {
+        self.annotations()
+            .get(DELETION_PROTECTION_ANNOTATION)
+            .is_some_and(|v| v == "true")
+    }
+
+    /// The reason for why this bucket may not be deleted, given the access keys of its garage
+    ///
+    /// Access keys only keep the bucket around until the cleanup timeout has `expired`, and not
+    /// at all once they are being deleted themselves.
+    fn deletion_blocker(&self, access_keys: &[AccessKey], expired: bool) -> Option<String> {
+        let name = self.name_any();
+        let forced = expired
+            || self
+                .annotations()
+                .get(FORCE_DELETE_ANNOTATION)
+                .is_some_and(|v| v == "true");
+
+        // Deleting a bucket which is still in use would orphan the credentials of its keys
+        let access_keys = access_keys
+            .iter()
+            .filter(|k| k.references(self) && k.metadata.deletion_timestamp.is_none())
+            .map(|k| format!("{}/{}", k.namespace().unwrap_or_default(), k.name_any()))
+            .collect::<Vec<_>>();
+
+        if self.is_deletion_protected() {
+            Some(format!(
+                "Bucket `{name}` has deletion protection enabled, remove the \
+                 `{DELETION_PROTECTION_ANNOTATION}` annotation to delete it"
+            ))
+        } else if !access_keys.is_empty() && !forced {
+            Some(format!(
+                "Bucket `{name}` is still used by access keys `{}`, delete them or set the \
+                 `{FORCE_DELETE_ANNOTATION}` annotation to delete it anyway",
+                access_keys.join("`, `"),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Add or remove the deletion protection finalizer depending on whether this bucket is
+    /// protected or still used by access keys
+    ///
+    /// Returns whether the deletion of this bucket is currently being blocked.
+    async fn sync_deletion_protection(&self, context: Arc<BucketContext>) -> Result<bool, Error> {
+        let name = self.name_any();
+        let deleted_at = self.metadata.deletion_timestamp.as_ref().map(|t| t.0);
+        let expired = cleanup_expired(
+            deleted_at,
+            context.common.config.cleanup_timeout,
+            Utc::now(),
+        );
+
+        let blocker = self.deletion_blocker(&context.access_keys, expired);
+        let protected = blocker.is_some();
+        let deleting = deleted_at.is_some();
+        let finalized = self
+            .finalizers()
+            .iter()
+            .any(|f| f == DELETION_PROTECTION_FINALIZER);
+
+        // Finalizers cannot be added once deletion has started, so only ever add them beforehand
+        let finalizers = if protected && !finalized && !deleting {
+            info!("Enabling deletion protection for bucket '{name}'");
+            let mut finalizers = self.finalizers().to_vec();
+            finalizers.push(DELETION_PROTECTION_FINALIZER.into());
+
+            Some(finalizers)
+        } else if !protected && finalized {
+            info!("Disabling deletion protection for bucket '{name}'");
+            let finalizers = self
+                .finalizers()
+                .iter()
+                .filter(|f| *f != DELETION_PROTECTION_FINALIZER)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            Some(finalizers)
+        } else {
+            None
+        };
+
+        if let Some(finalizers) = finalizers {
+            self.patch_finalizers(context.common.client.clone(), finalizers)
+                .await?;
+        }
+
+        // Let the user know why the bucket is sticking around
+        if let Some(blocker) = blocker.filter(|_| finalized && deleting) {
+            let recorder = context
+                .common
+                .diagnostics
+                .read()
+                .await
+                .recorder(context.common.client.clone(), self);
+            recorder
+                .publish(Event {
+                    type_: EventType::Warning,
+                    reason: "DeletionBlocked".into(),
+                    note: Some(blocker),
+                    action: "Deleting".into(),
+                    secondary: None,
+                })
+                .await?;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Let go of this bucket if it is being deleted while its garage can't check on its access
+    /// keys, unless deletion protection was explicitly enabled
+    pub(crate) async fn release_deletion_protection(&self, client: Client) -> Result<(), Error> {
+        let releasable = self.metadata.deletion_timestamp.is_some()
+            && !self.is_deletion_protected()
+            && self
+                .finalizers()
+                .iter()
+                .any(|f| f == DELETION_PROTECTION_FINALIZER);
+        if !releasable {
+            return Ok(());
+        }
+
+        info!("Releasing bucket '{}' for deletion", self.name_any());
+        let finalizers = self
+            .finalizers()
+            .iter()
+            .filter(|f| *f != DELETION_PROTECTION_FINALIZER)
+            .cloned()
+            .collect();
+
+        self.patch_finalizers(client, finalizers).await
+    }
+
+    /// Replace the finalizers of this bucket
+    async fn patch_finalizers(&self, client: Client, finalizers: Vec<String>) -> Result<(), Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalBucket(name.clone(), "missing namespace".into()))?;
+
+        // Guard against clobbering finalizers changed by someone else in the meantime
+        let patch = Patch::Merge(json!({
+            "metadata": {
+                "resourceVersion": self.resource_version(),
+                "finalizers": finalizers,
+            },
+        }));
+        Api::<Bucket>::namespaced(client, &namespace)
+            .patch(&name, &PatchParams::default(), &patch)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record a failed reconcile on the status of this bucket, rather than failing the reconcile
+    /// of its garage
+    pub(crate) async fn record_failure(&self, client: Client, error: Error) {
+        let name = self.name_any();
+        warn!("Could not reconcile bucket '{name}': {error}");
+        if let Err(e) = self.report_error(client, error).await {
+            warn!("Could not report failure of bucket '{name}': {e}");
+        }
+    }
+
+    /// Mark this bucket as errored, keeping the rest of its last known status
+    async fn report_error(&self, client: Client, error: Error) -> Result<(), Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalBucket(name.clone(), "missing namespace".into()))?;
+
+        let status = BucketStatus {
+            state: BucketState::Errored,
+            message: Some(error.to_string()),
+            ..self.status.clone().unwrap_or_default()
+        };
+        let new_status = Patch::Apply(json!({
+            "apiVersion": "deuxfleurs.fr/v0alpha",
+            "kind": "Bucket",
+            "status": status,
+        }));
+        let ps = PatchParams::apply("garage-operator").force();
+        Api::<Bucket>::namespaced(client, &namespace)
+            .patch_status(&name, &ps, &new_status)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    use super::*;
+    use crate::resources::NamespacedReference;
+
+    fn bucket(status: Option<BucketStatus>) -> Bucket {
+        let spec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+        }))
+        .unwrap();
+
+        Bucket {
+            status,
+            ..Bucket::new("test", spec)
+        }
+    }
+
+    #[test]
+    fn deletion_protection_requires_true() {
+        let mut bucket = bucket(None);
+        assert!(!bucket.is_deletion_protected());
+
+        for (value, protected) in [("false", false), ("yes", false), ("true", true)] {
+            bucket
+                .annotations_mut()
+                .insert(DELETION_PROTECTION_ANNOTATION.into(), value.into());
+            assert_eq!(bucket.is_deletion_protected(), protected);
+        }
+    }
+
+    fn access_key(bucket: &str) -> AccessKey {
+        let spec = serde_json::from_value(json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+            "bucketRef": { "name": bucket, "namespace": "default" },
+            "permissions": {},
+            "secretRef": { "name": "secret" },
+        }))
+        .unwrap();
+
+        let mut key = AccessKey::new("key", spec);
+        key.metadata.namespace = Some("default".into());
+        key
+    }
+
+    #[test]
+    fn access_keys_block_deletion_unless_forced() {
+        let mut bucket = bucket(None);
+        bucket.metadata.namespace = Some("default".into());
+        let keys = vec![access_key("test")];
+        assert!(bucket.deletion_blocker(&[], false).is_none());
+        assert!(bucket.deletion_blocker(&keys, false).is_some());
+
+        // Only keys of this bucket which are sticking around count
+        assert!(bucket
+            .deletion_blocker(&[access_key("other")], false)
+            .is_none());
+        let mut deleting = access_key("test");
+        deleting.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        assert!(bucket.deletion_blocker(&[deleting], false).is_none());
+
+        // Keys stop blocking once the cleanup timeout has expired
+        assert!(bucket.deletion_blocker(&keys, true).is_none());
+
+        bucket
+            .annotations_mut()
+            .insert(FORCE_DELETE_ANNOTATION.into(), "true".into());
+        assert!(bucket.deletion_blocker(&keys, false).is_none());
+
+        // Forcing does not override explicit deletion protection
+        bucket
+            .annotations_mut()
+            .insert(DELETION_PROTECTION_ANNOTATION.into(), "true".into());
+        assert!(bucket.deletion_blocker(&keys, true).is_some());
+    }
+
+    #[test]
+    fn global_alias_follows_template() {
+        let mut bucket = bucket(None);
+        bucket.metadata.namespace = Some("team".into());
+
+        let garage_with_template = |template: Option<&str>| {
+            let mut garage = Garage::new(
+                "garage",
+                serde_json::from_value(json!({
+                    "storage": { "meta": "meta", "data": ["data"] },
+                }))
+                .unwrap(),
+            );
+            garage.spec.global_alias_template = template.map(Into::into);
+            garage
+        };
+
+        let plain = garage_with_template(None);
+        assert_eq!(bucket.global_alias(&plain).unwrap(), "test");
+
+        let prefixed = garage_with_template(Some("{namespace}-{name}"));
+        assert_eq!(bucket.global_alias(&prefixed).unwrap(), "team-test");
+
+        // The lookup and creation must never use an alias garage would reject
+        for template in ["{name}_{namespace}", "{unknown}-{name}", "-{name}"] {
+            let garage = garage_with_template(Some(template));
+            assert!(matches!(
+                bucket.global_alias(&garage),
+                Err(Error::IllegalBucket(name, _)) if name == "test"
+            ));
+        }
+    }
+
+    #[test]
+    fn changing_garage_is_rejected() {
+        let reconciled = |namespace: &str, name: &str| BucketStatus {
+            id: "abc".into(),
+            garage_ref: Some(NamespacedReference {
+                name: name.into(),
+                namespace: namespace.into(),
+            }),
+            ..Default::default()
+        };
+
+        // Buckets which were never reconciled can point anywhere
+        assert!(bucket(None).rebind_error().is_none());
+        assert!(bucket(Some(BucketStatus::default()))
+            .rebind_error()
+            .is_none());
+
+        assert!(bucket(Some(reconciled("default", "garage")))
+            .rebind_error()
+            .is_none());
+        for (namespace, name) in [("default", "other"), ("other", "garage")] {
+            assert!(matches!(
+                bucket(Some(reconciled(namespace, name))).rebind_error(),
+                Some(Error::IllegalBucket(name, _)) if name == "test"
+            ));
+        }
+    }
+
+    #[test]
+    fn existing_buckets_are_adopted_once() {
+        let with_uid = |mut bucket: Bucket, uid: &str| {
+            bucket.metadata.uid = Some(uid.into());
+            bucket.metadata.namespace = Some("default".into());
+            bucket
+        };
+        let adopting = with_uid(bucket(None), "adopting");
+        let status = BucketStatus {
+            id: "abc".into(),
+            state: BucketState::Ready,
+            adopted: true,
+            ..Default::default()
+        };
+
+        // A pre-existing bucket which nothing manages yet can be adopted
+        let unrelated = with_uid(
+            bucket(Some(BucketStatus {
+                id: "def".into(),
+                ..status.clone()
+            })),
+            "unrelated",
+        );
+        assert!(adopting
+            .adoption_conflict("abc", &[adopting.clone(), unrelated])
+            .is_none());
+
+        // Re-adopting a bucket which this resource already manages is fine as well
+        let adopted = with_uid(bucket(Some(status.clone())), "adopting");
+        assert!(adopting.adoption_conflict("abc", &[adopted]).is_none());
+
+        let managed = with_uid(bucket(Some(status)), "managed");
+        assert!(matches!(
+            adopting.adoption_conflict("abc", &[managed]),
+            Some(Error::IllegalBucket(name, _)) if name == "test"
+        ));
+    }
+
+    #[test]
+    fn retrying_does_not_adopt_own_bucket() {
+        assert!(bucket(None).adopts("abc"));
+        assert!(bucket(Some(BucketStatus::default())).adopts("abc"));
+
+        // An errored bucket keeps the ID of the bucket it created before starting over
+        let retrying = bucket(Some(BucketStatus {
+            id: "abc".into(),
+            state: BucketState::Creating,
+            ..Default::default()
+        }));
+        assert!(!retrying.adopts("abc"));
+        assert!(retrying.adopts("def"));
+    }
+
+    #[test]
+    fn missing_id_is_an_error() {
+        assert!(matches!(
+            bucket(None).garage_id(),
+            Err(Error::IllegalBucket(name, _)) if name == "test"
+        ));
+        assert!(bucket(Some(BucketStatus::default())).garage_id().is_err());
+    }
+
+    #[test]
+    fn id_is_read_from_status() {
+        let status = BucketStatus {
+            id: "abc".into(),
+            ..Default::default()
+        };
+
+        assert_eq!(bucket(Some(status)).garage_id().unwrap(), "abc");
+    }
+}
@@ -1,37 +1,62 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use chrono::Utc;
 use indoc::formatdoc;
 use k8s_openapi::{
     api::{
         apps::v1::{Deployment, DeploymentSpec},
         core::v1::{
-            ConfigMap, ConfigMapVolumeSource, Container, ContainerPort, PersistentVolumeClaim,
-            PersistentVolumeClaimVolumeSource, PodSpec, PodTemplateSpec, Secret,
-            SecretVolumeSource, Service, ServicePort, ServiceSpec, Volume, VolumeMount,
+            ConfigMap, ConfigMapVolumeSource, Container, ContainerPort, EmptyDirVolumeSource,
+            EnvVar, EnvVarSource, KeyToPath, Lifecycle, Node, ObjectFieldSelector,
+            PersistentVolumeClaim, PersistentVolumeClaimVolumeSource, Pod, PodSpec,
+            PodTemplateSpec, Probe, ProjectedVolumeSource, Secret, SecretProjection,
+            SecretVolumeSource, Service, ServicePort, ServiceSpec, TCPSocketAction, Volume,
+            VolumeMount, VolumeProjection,
         },
     },
-    apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
+    apimachinery::pkg::{
+        api::resource::Quantity,
+        apis::meta::v1::{Condition, LabelSelector, Time},
+        util::intstr::IntOrString,
+    },
 };
 use kube::{
-    api::{ListParams, Patch, PatchParams},
-    runtime::controller::Action,
-    Api, Resource as _, ResourceExt as _,
+    api::{
+        ApiResource, DeleteParams, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams,
+    },
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+    },
+    Api, Client, Resource as _, ResourceExt as _,
 };
-use kube_quantity::ParsedQuantity;
 use serde_json::json;
-use tokio::try_join;
-use tracing::info;
+use tokio::{time::sleep, try_join};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
     admin_api::GarageAdmin,
     labels, meta,
-    resources::{Bucket, Garage, GarageState},
+    quantity::quantity_to_bytes,
+    resources::{
+        AccessKey, Bucket, ConfigMapReference, Garage, GarageConfig, GarageDbEngine, GarageState,
+        GarageStatus,
+    },
     Error,
 };
 
-use super::{bucket::BucketContext, CommonContext as Context, Reconcile};
+use super::{
+    apply_if_changed, bucket::BucketContext, missing_garage, repair_owners,
+    CommonContext as Context, Reconcile,
+};
 
 #[async_trait]
 impl Reconcile for Garage {
@@ -51,29 +76,83 @@ impl Reconcile for Garage {
         // Get the last known status of this garage, using the default if not present
         let status = self.status.clone().unwrap_or_default();
 
+        // Make sure that the spec is usable before creating anything for it
+        self.validate_storage()?;
+        self.validate_version(&context.garage_version)?;
+
+        // Changing the region of a live garage affects its data, so only do so when allowed to
+        if let Some(message) = self.region_change_blocker(&status) {
+            return self.block_region_change(context, status, message).await;
+        }
+
         // Always deploy all of the needed resources, as they are idempotent
         self.deploy_resources(context.clone()).await?;
 
+        // Check if the garage container is crash looping, since nothing else will work if it is
+        let crash_reason = if self.spec.suspended {
+            None
+        } else {
+            self.get_crash_loop_reason(context.clone()).await?
+        };
+
+        // The node which needs to be laid out by hand, if auto-layout is disabled
+        let mut manual_layout = None;
+
+        // Whether garage is running, if it was checked during this pass
+        let mut running = None;
+
+        // The admin API handle built during this pass, shared by everything that needs one
+        let mut pass_admin = None;
+
         // Handle what we need for now
         let (requeue, next_state): (Duration, GarageState) = match status.state {
+            // A suspended garage has nothing running, so just wait for it to be resumed
+            _ if self.spec.suspended => (context.config.ready_requeue, GarageState::Suspended),
+
+            // If garage keeps crashing, then mark it as errored so that it shows up in the status
+            _ if crash_reason.is_some() => (Duration::from_secs(15), GarageState::Errored),
+
             // If we need to create the instance, then do so now
             GarageState::Creating => {
                 info!(r#"Creating garage "{}/{}"#, namespace, name);
 
-                let next_state = if self.spec.auto_layout {
-                    GarageState::LayingOut
+                // Nothing can be done with garage until its admin API is up. The deployment only
+                // becomes available once garage is healthy, which needs a layout first.
+                let is_running = self.is_running(context.clone()).await?;
+                running = Some(is_running);
+
+                if !is_running {
+                    (Duration::from_secs(5), GarageState::Creating)
+                } else if let Some(next_state) = self.state_once_running() {
+                    (Duration::from_secs(2), next_state)
                 } else {
-                    GarageState::Ready
-                };
+                    // Without auto-layout, wait for the user to layout the cluster themselves
+                    let admin =
+                        pass_admin.insert(Arc::new(self.create_admin(context.clone()).await?));
+                    let (node_id, version) = admin.get_layout_version().await?;
+
+                    if version == 0 {
+                        manual_layout = Some(node_id);
+
+                        (Duration::from_secs(15), GarageState::Creating)
+                    } else {
+                        (Duration::from_secs(2), GarageState::Ready)
+                    }
+                }
+            }
 
-                (Duration::from_secs(2), next_state)
+            // Layout management may have been disabled in the middle of laying out
+            GarageState::LayingOut if !self.spec.manage_layout => {
+                (Duration::from_secs(2), GarageState::Ready)
             }
 
             // If we need to layout the garage instance, then attempt to do so now
             GarageState::LayingOut => {
                 // Actually layout the instance
-                let admin = self.create_admin(context.clone()).await?;
-                let done = admin.layout_instance(status.capacity).await?;
+                let admin = pass_admin.insert(Arc::new(self.create_admin(context.clone()).await?));
+                let node_zone = self.get_node_zone(context.clone()).await?;
+                let zone = self.spec.config.zone(node_zone.as_deref());
+                let done = admin.layout_instance(status.capacity, zone).await?;
 
                 // Keep trying to layout the server until it completes
                 (
@@ -86,56 +165,237 @@ impl Reconcile for Garage {
                 )
             }
 
-            // If we are done and ready, then reconcile the buckets and check again in an hour in case we missed something
+            // If we are done and ready, then reconcile the buckets and check again later in case we missed something
             GarageState::Ready => {
                 // Get all buckets that we own and reconcile them
                 // TODO: Should we do this in parallel?
                 // TODO: Listing requires filtering until `selectableFields` is stabilised and added to k8s (v1.30 and beyond)
-                let owned_buckets = bucket_handle
+                let (owned_buckets, other_buckets): (Vec<_>, Vec<_>) = bucket_handle
                     .list(&ListParams::default())
                     .await?
                     .into_iter()
-                    .filter(|b| {
+                    .partition(|b| {
                         b.spec.garage_ref.name == name && b.spec.garage_ref.namespace == namespace
                     });
 
+                // List the access keys once for all of the buckets of this pass
+                // TODO: Select by `spec.garageRef` once `selectableFields` is available, as for buckets
+                let (owned_keys, other_keys): (Vec<_>, Vec<_>) =
+                    Api::<AccessKey>::all(context.client.clone())
+                        .list(&ListParams::default())
+                        .await?
+                        .into_iter()
+                        .partition(|k| {
+                            k.spec.garage_ref.name == name
+                                && k.spec.garage_ref.namespace == namespace
+                        });
+
+                // Buckets and access keys referencing a garage that doesn't exist would
+                // otherwise never be reconciled, so flag them here
+                let garages = Api::<Garage>::all(context.client.clone())
+                    .list(&ListParams::default())
+                    .await?
+                    .items;
+                for bucket in &other_buckets {
+                    if let Some(e) = missing_garage(&bucket.spec.garage_ref, &garages) {
+                        bucket.record_failure(context.client.clone(), e).await;
+                        bucket
+                            .release_deletion_protection(context.client.clone())
+                            .await?;
+                    }
+                }
+                for key in &other_keys {
+                    if let Some(e) = missing_garage(&key.spec.garage_ref, &garages) {
+                        key.record_failure(context.client.clone(), e).await;
+                    }
+                }
+
+                // Share a single admin handle with all of the buckets for this pass
+                let admin = pass_admin.insert(Arc::new(self.create_admin(context.clone()).await?));
                 let bucket_context = Arc::new(BucketContext {
                     common: context.clone(),
                     owner: self.clone(),
+                    admin: admin.clone(),
+                    access_keys: owned_keys,
                 });
-                for bucket in owned_buckets {
-                    bucket.reconcile(bucket_context.clone()).await?;
+                for bucket in &owned_buckets {
+                    // A broken bucket should not count as a failure of the whole garage
+                    if let Err(e) = bucket.reconcile(bucket_context.clone()).await {
+                        bucket.record_failure(context.client.clone(), e).await;
+                    }
+                }
+
+                // Access keys of deleted buckets are no longer reconciled by any bucket, so
+                // revoke their access here
+                let orphaned_keys = bucket_context
+                    .access_keys
+                    .iter()
+                    .filter(|k| k.bucket_missing(&owned_buckets));
+                for key in orphaned_keys {
+                    // A key which can't be revoked should not count as a failure of the garage
+                    if let Err(e) = key
+                        .report_missing_bucket(context.client.clone(), &bucket_context.admin)
+                        .await
+                    {
+                        warn!(
+                            "Could not revoke access of key '{}' to its missing bucket: {e}",
+                            key.name_any()
+                        );
+                    }
                 }
 
-                (Duration::from_secs(60 * 60), GarageState::Ready)
+                (context.config.ready_requeue, GarageState::Ready)
             }
 
             // If we have encountered an error, try to start over in 15 seconds
             GarageState::Errored => (Duration::from_secs(15), GarageState::Creating),
+
+            // If we were resumed, then go through creation again to make sure everything is up
+            GarageState::Suspended => (Duration::from_secs(2), GarageState::Creating),
         };
 
+        // Access keys are only checked while garage is ready, so don't keep buckets from being
+        // deleted in the meantime
+        if next_state != GarageState::Ready {
+            self.release_buckets(context.client.clone()).await?;
+        }
+
         // always overwrite status object with what we saw
-        let capacity = {
-            let caps = self.get_capacities(context.clone()).await?;
-            let cap = caps
-                .into_iter()
-                .fold(ParsedQuantity::default(), |acc, cur| acc + cur);
+        let capacity = match self.layout_capacity()? {
+            Some(capacity) => capacity,
+            None => {
+                let caps = self.get_capacities(context.clone()).await?;
+                caps.iter()
+                    .try_fold(0i64, |acc, cur| acc.checked_add(*cur))
+                    .ok_or_else(|| {
+                        Error::IllegalGarage(name.clone(), "total capacity is too large".into())
+                    })?
+            }
+        };
+
+        // Count the nodes in the cluster, keeping the last known counts if garage can't tell us
+        let (nodes_healthy, nodes_total) = if self.spec.suspended {
+            // A suspended garage has no admin API to reach
+            context.metrics.admin_reachable(self, None);
+            (0, status.nodes_total)
+        } else if running == Some(false) {
+            // Garage was just seen not running, so there is no point in waiting on its admin API
+            context.metrics.admin_reachable(self, Some(false));
+            (status.nodes_healthy, status.nodes_total)
+        } else {
+            // Reuse the admin handle of this pass, only building one if nothing needed it yet
+            let admin = match pass_admin {
+                Some(admin) => Ok(admin),
+                None => self.create_admin(context.clone()).await.map(Arc::new),
+            };
+            let counts = match admin {
+                Ok(admin) => admin.get_node_counts().await,
+                Err(e) => Err(e),
+            };
+            context.metrics.admin_reachable(self, Some(counts.is_ok()));
+
+            counts.unwrap_or_else(|e| {
+                warn!(r#"Could not count nodes of garage "{namespace}/{name}": {e}"#);
+                (status.nodes_healthy, status.nodes_total)
+            })
+        };
+
+        // Report whether garage is running, as it was last seen while creating it
+        let mut conditions = Vec::new();
+        match running {
+            Some(running) => {
+                let (reason, message) = if running {
+                    ("PodRunning", "garage is up")
+                } else {
+                    (
+                        "PodNotRunning",
+                        "waiting for a garage pod to be running with its admin API listening",
+                    )
+                };
+                conditions.push(condition(
+                    &status,
+                    self.metadata.generation,
+                    GarageStatus::RUNNING,
+                    running,
+                    reason,
+                    message.into(),
+                ));
+            }
 
-            cap.to_bytes_i64().unwrap()
+            // Keep reporting the last known availability, unless nothing is supposed to be up
+            None if !self.spec.suspended => {
+                conditions.extend(status.condition(GarageStatus::RUNNING).cloned())
+            }
+            None => {}
+        }
+
+        // Let the user know how to layout the instance, but only once so as not to spam events
+        if let Some(node_id) = manual_layout {
+            let node_zone = self.get_node_zone(context.clone()).await?;
+            let zone = self.spec.config.zone(node_zone.as_deref());
+            let commands = manual_layout_commands(&name, &node_id, zone, capacity);
+            let previous = status.condition(GarageStatus::MANUAL_LAYOUT_REQUIRED);
+            if previous.is_none() {
+                let recorder = context
+                    .diagnostics
+                    .read()
+                    .await
+                    .recorder(context.client.clone(), self);
+                recorder
+                    .publish(Event {
+                        type_: EventType::Warning,
+                        reason: GarageStatus::MANUAL_LAYOUT_REQUIRED.into(),
+                        note: Some(format!("Layout the cluster by running: {commands}")),
+                        action: "LayingOut".into(),
+                        secondary: None,
+                    })
+                    .await?;
+            }
+
+            let message = format!(
+                "auto-layout is disabled and the cluster has not been laid out, run: {commands}"
+            );
+            conditions.push(condition(
+                &status,
+                self.metadata.generation,
+                GarageStatus::MANUAL_LAYOUT_REQUIRED,
+                true,
+                "LayoutVersionZero",
+                message,
+            ));
+        }
+
+        // User supplied configs are not rendered by us, so the last applied region still stands
+        let region = if self.spec.config_from.is_some() {
+            status.region.clone()
+        } else {
+            Some(self.spec.config.region.clone())
         };
 
+        let action = context
+            .config
+            .next_action(requeue, settles(&status.state, &next_state));
+
         let new_status = Patch::Apply(json!({
             "apiVersion": "deuxfleurs.fr/v0alpha",
             "kind": "Garage",
-            "status": {
-                "state": next_state,
-                "capacity": capacity,
+            "status": GarageStatus {
+                capacity,
+                state: next_state,
+                message: crash_reason,
+                nodes_total,
+                nodes_healthy,
+                nodes_friendly: format!("{nodes_healthy}/{nodes_total}"),
+                consecutive_failures: 0,
+                conditions,
+                region,
+                observed_generation: self.metadata.generation,
             },
         }));
         let ps = PatchParams::apply("garage-operator").force(); // TODO: Why is this force?
         let _o = garage_handle.patch_status(&name, &ps, &new_status).await?;
 
-        Ok(Action::requeue(requeue))
+        Ok(action)
     }
 
     async fn deploy_resources(&self, context: Arc<Context>) -> Result<(), Error> {
@@ -144,6 +404,7 @@ impl Reconcile for Garage {
             self.create_config(context.clone()),
             self.create_secrets(context.clone()),
             self.create_services(context.clone()),
+            self.create_service_monitor(context.clone()),
         )?;
 
         // Now deploy with the above resources
@@ -170,10 +431,20 @@ impl Garage {
 
             let secrets = Api::<Secret>::namespaced(context.client.clone(), &namespace);
 
-            let secret = secrets
-                .get_opt(admin_token_name)
-                .await?
-                .ok_or_else(|| Error::MissingSecret(admin_token_name.clone()))?;
+            // The secret may have only just been created, so give the API server a few chances
+            // to catch up before deciding that it is actually missing
+            let mut secret = None;
+            for attempt in 0..ADMIN_SECRET_ATTEMPTS {
+                secret = secrets.get_opt(admin_token_name).await?;
+                if secret.is_some() {
+                    break;
+                }
+
+                if attempt + 1 < ADMIN_SECRET_ATTEMPTS {
+                    sleep(Duration::from_millis(250 << attempt)).await;
+                }
+            }
+            let secret = secret.ok_or_else(|| Error::MissingSecret(admin_token_name.clone()))?;
             let token = secret
                 .data
                 .ok_or_else(|| Error::MissingSecretData(admin_token_name.clone()))?;
@@ -181,42 +452,255 @@ impl Garage {
                 .get("key")
                 .ok_or_else(|| Error::MissingSecretData(admin_token_name.clone()))?;
 
-            String::from_utf8(token.0.clone()).unwrap()
+            String::from_utf8(token.0.clone()).map_err(|_| {
+                Error::IllegalGarage(self.name_any(), "admin token is not valid UTF-8".into())
+            })?
         };
 
         // Construct the admin API with our secret
-        GarageAdmin::with_secret(self, &token)
+        GarageAdmin::with_secret(self, &token, &context.admin_clients, &context.metrics)
+    }
+
+    /// The reason for why the config of this garage may not be updated, if its region changed
+    fn region_change_blocker(&self, status: &GarageStatus) -> Option<String> {
+        let previous = status.region.as_ref()?;
+        let region = &self.spec.config.region;
+        let allowed = self
+            .annotations()
+            .get(ALLOW_REGION_CHANGE_ANNOTATION)
+            .is_some_and(|v| v == region);
+
+        if self.spec.config_from.is_some() || previous == region || allowed {
+            return None;
+        }
+
+        Some(format!(
+            "region changed from \"{previous}\" to \"{region}\", which affects existing data, \
+             set the `{ALLOW_REGION_CHANGE_ANNOTATION}` annotation to \"{region}\" to apply it \
+             anyway"
+        ))
+    }
+
+    /// Mark this garage as errored until its change of region is reverted or allowed
+    async fn block_region_change(
+        &self,
+        context: Arc<Context>,
+        status: GarageStatus,
+        message: String,
+    ) -> Result<Action, Error> {
+        // Only let the user know once, so as not to spam events
+        if status
+            .condition(GarageStatus::REGION_CHANGE_BLOCKED)
+            .is_none()
+        {
+            let recorder = context
+                .diagnostics
+                .read()
+                .await
+                .recorder(context.client.clone(), self);
+            recorder
+                .publish(Event {
+                    type_: EventType::Warning,
+                    reason: GarageStatus::REGION_CHANGE_BLOCKED.into(),
+                    note: Some(message.clone()),
+                    action: "Configuring".into(),
+                    secondary: None,
+                })
+                .await?;
+        }
+
+        let mut conditions: Vec<_> = status
+            .conditions
+            .iter()
+            .filter(|c| c.type_ != GarageStatus::REGION_CHANGE_BLOCKED)
+            .cloned()
+            .collect();
+        conditions.push(condition(
+            &status,
+            self.metadata.generation,
+            GarageStatus::REGION_CHANGE_BLOCKED,
+            true,
+            "RegionChanged",
+            message.clone(),
+        ));
+
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+
+        let new_status = Patch::Apply(json!({
+            "apiVersion": "deuxfleurs.fr/v0alpha",
+            "kind": "Garage",
+            "status": GarageStatus {
+                state: GarageState::Errored,
+                message: Some(message),
+                conditions,
+                ..status
+            },
+        }));
+        let ps = PatchParams::apply("garage-operator").force();
+        Api::<Garage>::namespaced(context.client.clone(), &namespace)
+            .patch_status(&name, &ps, &new_status)
+            .await?;
+
+        Ok(Action::requeue(Duration::from_secs(15)))
     }
 
     /// Create a [ConfigMap] for storing the garage's configuration
     async fn create_config(&self, context: Arc<Context>) -> Result<(), Error> {
-        let client = context.client.clone();
-        let config = &self.spec.config;
-        let ports = &config.ports;
+        // User supplied configs are used as-is
+        if let Some(config_from) = &self.spec.config_from {
+            return self.validate_user_config(context, config_from).await;
+        }
+
+        let garage_config = self.render_config(context.clone()).await?;
+
+        // Make the ConfigMap for the config
+        let owner = self.controller_owner_ref(&()).unwrap();
+        let name = self.prefixed_name("config");
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".to_string()))?;
+        let cm = ConfigMap {
+            metadata: meta! { owners: vec![owner], name: Some(name.clone()) },
+            data: Some(BTreeMap::from([("garage.toml".into(), garage_config)])),
+
+            binary_data: None,
+            immutable: None,
+        };
+
+        // Apply the config
+        let configs = Api::<ConfigMap>::namespaced(context.client.clone(), &namespace);
+        apply_if_changed(&configs, &name, cm, &context.metrics).await?;
+
+        Ok(())
+    }
+
+    /// Render the garage.toml which the operator deploys for this garage
+    ///
+    /// Garages which supply their own config through `configFrom` have nothing to render.
+    pub async fn render_config(&self, context: Arc<Context>) -> Result<String, Error> {
+        if let Some(config_from) = &self.spec.config_from {
+            return Err(Error::IllegalGarage(
+                self.name_any(),
+                format!(r#"config is supplied by "{}""#, config_from.name),
+            ));
+        }
 
         // Fetch info about the meta and data mounts
         let data_sources = self.get_capacities(context.clone()).await?;
 
-        // Map them into the expected configuration format
-        let data_sources = data_sources
-            .into_iter()
-            .enumerate()
-            .map(|(index, capacity)| {
-                format!(
-                    r#"{{ path = "{}", capacity = "{}B" }}"#,
-                    get_mount_for_index(index),
-                    capacity.to_bytes_usize().unwrap(),
-                )
-            })
-            .collect::<Vec<_>>();
+        self.render_config_with(&context.garage_version, &data_sources)
+    }
+
+    /// Render the garage.toml for this garage from the capacities of its data volumes
+    fn render_config_with(
+        &self,
+        default_version: &str,
+        data_sources: &[i64],
+    ) -> Result<String, Error> {
+        let config = &self.spec.config;
+        let ports = &config.ports;
+
+        // Map the data mounts into the expected configuration format
+        let data_dir = render_data_dir(data_sources, self.spec.storage.shared_data.is_some());
+
+        // Map the optional entries, validating them along the way
+        let s3_api_root_domain = match &config.s3_api_root_domain {
+            Some(domain) if !domain.starts_with('.') => {
+                return Err(Error::IllegalGarage(
+                    self.name_any(),
+                    format!(r#"s3 api root domain "{domain}" must start with a '.'"#),
+                ))
+            }
+            Some(domain) => format!("\nroot_domain = \"{domain}\""),
+            None => String::new(),
+        };
+        let s3_api_options = render_options(
+            self,
+            &config.s3_api_options,
+            &["s3_region", "api_bind_addr", "root_domain"],
+        )?;
+        let options = render_options(self, &config.options, RESERVED_OPTIONS)?;
+        let block_ram_buffer_max = match &config.block_ram_buffer_max {
+            Some(max) => {
+                let version = self.version(default_version);
+                if !supports_block_ram_buffer_max(version) {
+                    return Err(Error::IllegalGarage(
+                        self.name_any(),
+                        format!("block ram buffer max is not supported by garage {version}"),
+                    ));
+                }
+
+                let bytes = quantity_to_bytes(max)
+                    .ok()
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| {
+                        Error::IllegalGarage(
+                            self.name_any(),
+                            format!("invalid block ram buffer max: {}", max.0),
+                        )
+                    })?;
+
+                format!("\nblock_ram_buffer_max = \"{bytes}B\"")
+            }
+            None => String::new(),
+        };
+        let db_cache_size =
+            render_db_cache_size(config).map_err(|e| Error::IllegalGarage(self.name_any(), e))?;
+        let admin_bind_addr =
+            render_admin_bind_addr(self, config.admin_bind_address.as_deref(), ports.admin)?;
+        let trace_sink = match &config.trace_sink {
+            Some(sink) => {
+                let valid = reqwest::Url::parse(sink)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+                if !valid {
+                    return Err(Error::IllegalGarage(
+                        self.name_any(),
+                        format!(r#"trace sink "{sink}" must be an http(s) endpoint"#),
+                    ));
+                }
+
+                // The URL parser drops some characters, so escape the value as given
+                format!("\ntrace_sink = {}", toml::Value::String(sink.clone()))
+            }
+            None => String::new(),
+        };
+
+        let replication = match config.replication_factor {
+            Some(factor) => {
+                let version = self.version(default_version);
+                if !supports_replication_factor(version) {
+                    return Err(Error::IllegalGarage(
+                        self.name_any(),
+                        format!("replication factor is not supported by garage {version}"),
+                    ));
+                }
+
+                // Garage can only place copies of data in distinct zones, so make sure that
+                // there are enough of them among the nodes laid out by the operator
+                validate_replication(&config.replication_mode, factor, self.managed_zones())
+                    .map_err(|e| Error::IllegalGarage(self.name_any(), e))?;
+
+                // The consistency of the old mode is configured separately alongside the factor
+                match consistency_mode(&config.replication_mode) {
+                    Some(consistency) => format!(
+                        "replication_factor = {factor}\nconsistency_mode = \"{consistency}\""
+                    ),
+                    None => format!("replication_factor = {factor}"),
+                }
+            }
+            None => format!(r#"replication_mode = "{}""#, config.replication_mode),
+        };
 
         // Construct the config
         let garage_config = formatdoc! {r#"
                 metadata_dir = "/mnt/meta"
-                data_dir     = [ {data_sources} ]
-                db_engine    = "lmdb"
+                data_dir     = {data_dir}
+                db_engine    = "{db_engine}"{db_cache_size}{block_ram_buffer_max}{options}
 
-                replication_mode = "{replication_mode}"
+                {replication}
 
                 # RPC info
                 rpc_secret_file = "/secrets/rpc.key"
@@ -224,45 +708,80 @@ impl Garage {
 
                 [s3_api]
                 s3_region = "{region}"
-                api_bind_addr = "[::]:{port_s3}"
+                api_bind_addr = "[::]:{port_s3}"{s3_api_root_domain}{s3_api_options}
 
                 [s3_web]
                 bind_addr = "[::]:{port_web}"
                 root_domain = ".web.garage.localhost"
-                index = "index.html"
+                index = "{web_index}"
 
                 [admin]
-                api_bind_addr = "0.0.0.0:{port_admin}"
-                admin_token_file = "/secrets/admin.key"
+                api_bind_addr = "{admin_bind_addr}"
+                admin_token_file = "/secrets/admin.key"{trace_sink}
             "#,
-            data_sources = data_sources.join(","),
-            port_admin = ports.admin,
             port_rpc = ports.rpc,
             port_s3 = ports.s3_api,
             port_web = ports.s3_web,
             region = config.region,
-            replication_mode = config.replication_mode,
+            web_index = config.web_index,
+            db_engine = config.db_engine,
         };
 
-        // Make the ConfigMap for the config
-        let owner = self.controller_owner_ref(&()).unwrap();
-        let name = self.prefixed_name("config");
+        // Catch anything which was not escaped properly before garage crash loops on it
+        check_rendered_config(self, &garage_config)?;
+
+        Ok(garage_config)
+    }
+
+    /// Validate that a user supplied config can be used with the resources that we deploy
+    async fn validate_user_config(
+        &self,
+        context: Arc<Context>,
+        config_from: &ConfigMapReference,
+    ) -> Result<(), Error> {
+        let name = self.name_any();
         let namespace = self
             .namespace()
-            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".to_string()))?;
-        let cm = ConfigMap {
-            metadata: meta! { owners: vec![owner], name: Some(name.clone()) },
-            data: Some(BTreeMap::from([("garage.toml".into(), garage_config)])),
-
-            binary_data: None,
-            immutable: None,
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+        let illegal = |reason: String| {
+            Error::IllegalGarage(
+                name.clone(),
+                format!(r#"config "{}" {reason}"#, config_from.name),
+            )
         };
 
-        // Apply the config
-        let configs = Api::<ConfigMap>::namespaced(client.clone(), &namespace);
-        let params = PatchParams::apply("garage-operator");
-        let patch = Patch::Apply(cm);
-        configs.patch(&name, &params, &patch).await?;
+        // Fetch the config from the user's ConfigMap
+        let configs = Api::<ConfigMap>::namespaced(context.client.clone(), &namespace);
+        let config_map = configs
+            .get_opt(&config_from.name)
+            .await?
+            .ok_or_else(|| illegal("does not exist".into()))?;
+        let raw_config = config_map
+            .data
+            .and_then(|mut data| data.remove(&config_from.key))
+            .ok_or_else(|| illegal(format!(r#"is missing key "{}""#, config_from.key)))?;
+        let garage_config = raw_config
+            .parse::<toml::Table>()
+            .map_err(|e| illegal(format!("is not valid TOML: {e}")))?;
+
+        // Make sure that the secrets are read from where we mount them
+        let rpc_secret_file = garage_config.get("rpc_secret_file");
+        let admin_token_file = garage_config
+            .get("admin")
+            .and_then(|admin| admin.get("admin_token_file"));
+        let expected = [
+            ("rpc_secret_file", rpc_secret_file, "/secrets/rpc.key"),
+            (
+                "admin.admin_token_file",
+                admin_token_file,
+                "/secrets/admin.key",
+            ),
+        ];
+        for (key, value, path) in expected {
+            if value.and_then(|v| v.as_str()) != Some(path) {
+                return Err(illegal(format!(r#"must set "{key}" to "{path}""#)));
+            }
+        }
 
         Ok(())
     }
@@ -289,29 +808,153 @@ impl Garage {
         ];
 
         // Generate metadata needed for managing the deployment through the operator
-        let labels = labels! { instance: name.clone() };
+        let selector = labels! { instance: name.clone() };
+        let labels = self.labels(&context.garage_version);
         let owner = self.controller_owner_ref(&()).unwrap();
+        let (secret_mounts, secret_volumes) = self.secret_volumes();
+
+        // The IP of the pod has to be filled into the config on every start, as it changes
+        let binds_to_pod_ip = self.binds_to_pod_ip();
 
         // Create the deployment
         let deployment_data = Deployment {
             metadata: meta! {
                 owners: vec![owner.clone()],
-                name: Some(name.clone())
+                name: Some(name.clone()),
+                labels: Some(labels)
             },
 
             spec: Some(DeploymentSpec {
+                replicas: Some(if self.spec.suspended { 0 } else { 1 }),
                 selector: LabelSelector {
-                    match_labels: Some(labels.clone()),
+                    match_labels: Some(selector),
                     match_expressions: None,
                 },
                 template: PodTemplateSpec {
-                    metadata: Some(meta! { owners: vec![owner], labels: Some(labels) }),
+                    metadata: Some(meta! {
+                        owners: vec![owner],
+                        labels: Some(self.pod_labels(&context.garage_version))
+                    }),
                     spec: Some(PodSpec {
+                        service_account_name: self.spec.service_account_name.clone(),
+                        automount_service_account_token: Some(
+                            self.spec.automount_service_account_token.unwrap_or(false),
+                        ),
+                        priority_class_name: self.spec.priority_class_name.clone(),
+                        termination_grace_period_seconds: Some(
+                            self.spec.termination_grace_period_seconds,
+                        ),
+
+                        // Fill in the IP of the pod before garage reads its config
+                        init_containers: binds_to_pod_ip.then(|| {
+                            vec![Container {
+                                image: Some(POD_IP_RENDER_IMAGE.into()),
+                                name: "render-config".into(),
+                                command: Some(
+                                    [
+                                        "sh",
+                                        "-c",
+                                        POD_IP_RENDER_SCRIPT,
+                                        "render-config",
+                                        "/etc/garage/garage.toml",
+                                        "/run/garage/garage.toml",
+                                    ]
+                                    .map(Into::into)
+                                    .to_vec(),
+                                ),
+                                env: Some(vec![EnvVar {
+                                    name: "POD_IP".into(),
+                                    value_from: Some(EnvVarSource {
+                                        field_ref: Some(ObjectFieldSelector {
+                                            field_path: "status.podIP".into(),
+                                            api_version: None,
+                                        }),
+                                        ..Default::default()
+                                    }),
+                                    value: None,
+                                }]),
+                                volume_mounts: Some(vec![
+                                    VolumeMount {
+                                        name: "config".into(),
+                                        read_only: Some(true),
+                                        mount_path: "/etc/garage".into(),
+                                        ..Default::default()
+                                    },
+                                    VolumeMount {
+                                        name: "rendered-config".into(),
+                                        mount_path: "/run/garage".into(),
+                                        ..Default::default()
+                                    },
+                                ]),
+                                ..Default::default()
+                            }]
+                        }),
+
                         // Use the official container from garage
                         containers: vec![Container {
-                            image: Some(format!("dxflrs/garage:{}", context.garage_version)),
+                            image: Some(self.image(&context.garage_version)),
+                            image_pull_policy: self
+                                .spec
+                                .image_pull_policy
+                                .as_ref()
+                                .map(ToString::to_string),
                             name: "garage".into(),
 
+                            // Allow for overriding how garage is started
+                            command: Some(self.spec.command.clone())
+                                .filter(|command| !command.is_empty()),
+                            args: Some(self.spec.args.clone()).filter(|args| !args.is_empty()),
+                            working_dir: self.spec.working_dir.clone(),
+
+                            // Let garage know about its own pod, e.g. for templating addresses
+                            env: Some(
+                                [
+                                    ("POD_IP", "status.podIP"),
+                                    ("POD_NAME", "metadata.name"),
+                                    ("POD_NAMESPACE", "metadata.namespace"),
+                                ]
+                                .into_iter()
+                                .map(|(name, field_path)| EnvVar {
+                                    name: name.into(),
+                                    value_from: Some(EnvVarSource {
+                                        field_ref: Some(ObjectFieldSelector {
+                                            field_path: field_path.into(),
+                                            api_version: None,
+                                        }),
+                                        ..Default::default()
+                                    }),
+                                    value: None,
+                                })
+                                // Set through the environment so that it also applies to user
+                                // supplied configs
+                                .chain(self.spec.secrets.allow_world_readable.then(|| EnvVar {
+                                    name: "GARAGE_ALLOW_WORLD_READABLE_SECRETS".into(),
+                                    value: Some("true".into()),
+                                    value_from: None,
+                                }))
+                                .collect(),
+                            ),
+
+                            // Give garage a chance to prepare for shutdown, if requested
+                            lifecycle: self.spec.pre_stop.clone().map(|pre_stop| Lifecycle {
+                                pre_stop: Some(pre_stop),
+                                post_start: None,
+                            }),
+
+                            // Give garage plenty of time to open its database before checking on it.
+                            // This only checks that the admin API is listening, since `/health`
+                            // fails until the node has a layout, which we can only apply once the
+                            // admin API is reachable.
+                            startup_probe: Some(self.spec.startup_probe.clone().unwrap_or(Probe {
+                                tcp_socket: Some(TCPSocketAction {
+                                    port: IntOrString::Int(ports.admin as i32),
+                                    host: None,
+                                }),
+                                period_seconds: Some(10),
+                                failure_threshold: Some(60),
+                                ..Default::default()
+                            })),
+
                             // Export the ports that we need
                             ports: Some(
                                 service_ports
@@ -327,34 +970,29 @@ impl Garage {
                             // Mount the needed secrets, config, and volumes
                             volume_mounts: Some(
                                 [
-                                    vec![
-                                        VolumeMount {
-                                            name: "config".into(),
-                                            read_only: Some(true),
-                                            mount_path: "/etc/garage.toml".into(),
-                                            sub_path: Some("garage.toml".into()),
-                                            ..Default::default()
-                                        },
-                                        VolumeMount {
-                                            name: "admin-secret".into(),
-                                            read_only: Some(true),
-                                            mount_path: "/secrets/admin.key".into(),
-                                            sub_path: Some("key".into()),
-                                            ..Default::default()
-                                        },
-                                        VolumeMount {
-                                            name: "rpc-secret".into(),
-                                            read_only: Some(true),
-                                            mount_path: "/secrets/rpc.key".into(),
-                                            sub_path: Some("key".into()),
-                                            ..Default::default()
-                                        },
-                                        VolumeMount {
-                                            name: "meta-pvc".into(),
-                                            mount_path: "/mnt/meta".into(),
-                                            ..Default::default()
+                                    vec![VolumeMount {
+                                        name: if binds_to_pod_ip {
+                                            "rendered-config".into()
+                                        } else {
+                                            "config".into()
                                         },
-                                    ],
+                                        read_only: Some(true),
+                                        mount_path: "/etc/garage.toml".into(),
+                                        sub_path: Some(
+                                            self.spec
+                                                .config_from
+                                                .as_ref()
+                                                .map(|c| c.key.clone())
+                                                .unwrap_or("garage.toml".into()),
+                                        ),
+                                        ..Default::default()
+                                    }],
+                                    secret_mounts,
+                                    vec![VolumeMount {
+                                        name: "meta-pvc".into(),
+                                        mount_path: "/mnt/meta".into(),
+                                        ..Default::default()
+                                    }],
                                     self.spec
                                         .storage
                                         .data
@@ -366,6 +1004,21 @@ impl Garage {
                                             ..Default::default()
                                         })
                                         .collect(),
+                                    // Shared data directories are mounted after the dedicated ones
+                                    storage
+                                        .shared_data
+                                        .iter()
+                                        .flat_map(|shared| shared.paths.iter())
+                                        .enumerate()
+                                        .map(|(index, path)| VolumeMount {
+                                            name: "data-shared-pvc".into(),
+                                            mount_path: get_mount_for_index(
+                                                storage.data.len() + index,
+                                            ),
+                                            sub_path: Some(path.sub_path.clone()),
+                                            ..Default::default()
+                                        })
+                                        .collect(),
                                 ]
                                 .concat(),
                             ),
@@ -376,58 +1029,39 @@ impl Garage {
                         // and how they are mapped to existing resources
                         volumes: Some(
                             [
-                                vec![
-                                    Volume {
-                                        name: "config".into(),
-                                        config_map: Some(ConfigMapVolumeSource {
-                                            name: Some(self.prefixed_name("config")),
-                                            ..Default::default()
-                                        }),
-                                        ..Default::default()
-                                    },
-                                    Volume {
-                                        name: "admin-secret".into(),
-                                        secret: Some(SecretVolumeSource {
-                                            secret_name: Some(
-                                                self.spec
-                                                    .secrets
-                                                    .admin
-                                                    .as_ref()
-                                                    .and_then(|a| a.name.clone())
-                                                    .unwrap_or(self.prefixed_name("admin.key")),
-                                            ),
-                                            default_mode: Some(0o600),
-                                            ..Default::default()
-                                        }),
-                                        ..Default::default()
-                                    },
-                                    Volume {
-                                        name: "rpc-secret".into(),
-                                        secret: Some(SecretVolumeSource {
-                                            secret_name: Some(
-                                                self.spec
-                                                    .secrets
-                                                    .rpc
-                                                    .as_ref()
-                                                    .and_then(|a| a.name.clone())
-                                                    .unwrap_or(self.prefixed_name("rpc.key")),
-                                            ),
-                                            default_mode: Some(0o600),
-                                            ..Default::default()
-                                        }),
-                                        ..Default::default()
-                                    },
-                                    Volume {
-                                        name: "meta-pvc".into(),
-                                        persistent_volume_claim: Some(
-                                            PersistentVolumeClaimVolumeSource {
-                                                claim_name: storage.meta.clone(),
-                                                read_only: None,
-                                            },
+                                vec![Volume {
+                                    name: "config".into(),
+                                    config_map: Some(ConfigMapVolumeSource {
+                                        name: Some(
+                                            self.spec
+                                                .config_from
+                                                .as_ref()
+                                                .map(|c| c.name.clone())
+                                                .unwrap_or(self.prefixed_name("config")),
                                         ),
                                         ..Default::default()
-                                    },
-                                ],
+                                    }),
+                                    ..Default::default()
+                                }],
+                                binds_to_pod_ip
+                                    .then(|| Volume {
+                                        name: "rendered-config".into(),
+                                        empty_dir: Some(EmptyDirVolumeSource::default()),
+                                        ..Default::default()
+                                    })
+                                    .into_iter()
+                                    .collect(),
+                                secret_volumes,
+                                vec![Volume {
+                                    name: "meta-pvc".into(),
+                                    persistent_volume_claim: Some(
+                                        PersistentVolumeClaimVolumeSource {
+                                            claim_name: storage.meta.clone(),
+                                            read_only: None,
+                                        },
+                                    ),
+                                    ..Default::default()
+                                }],
                                 self.spec
                                     .storage
                                     .data
@@ -444,6 +1078,20 @@ impl Garage {
                                         ..Default::default()
                                     })
                                     .collect(),
+                                storage
+                                    .shared_data
+                                    .iter()
+                                    .map(|shared| Volume {
+                                        name: "data-shared-pvc".into(),
+                                        persistent_volume_claim: Some(
+                                            PersistentVolumeClaimVolumeSource {
+                                                claim_name: shared.claim.clone(),
+                                                read_only: None,
+                                            },
+                                        ),
+                                        ..Default::default()
+                                    })
+                                    .collect(),
                             ]
                             .concat(),
                         ),
@@ -457,13 +1105,97 @@ impl Garage {
 
         // Apply the deployment
         let deployments = Api::<Deployment>::namespaced(client.clone(), &namespace);
-        let params = PatchParams::apply("garage-operator");
-        let patch = Patch::Apply(deployment_data);
-        deployments.patch(&name, &params, &patch).await?;
+        apply_if_changed(&deployments, &name, deployment_data, &context.metrics).await?;
 
         Ok(())
     }
 
+    /// The volumes and mounts needed for exposing the secrets to garage
+    ///
+    /// Garage reads the secrets from `/secrets/admin.key` and `/secrets/rpc.key`, which are
+    /// either mounted from their own secret volumes or projected together into `/secrets`.
+    fn secret_volumes(&self) -> (Vec<VolumeMount>, Vec<Volume>) {
+        let secrets = &self.spec.secrets;
+        let admin_secret = secrets
+            .admin
+            .as_ref()
+            .and_then(|a| a.name.clone())
+            .unwrap_or(self.prefixed_name("admin.key"));
+        let rpc_secret = secrets
+            .rpc
+            .as_ref()
+            .and_then(|a| a.name.clone())
+            .unwrap_or(self.prefixed_name("rpc.key"));
+
+        if secrets.projected {
+            let project = |name: String, path: &str| VolumeProjection {
+                secret: Some(SecretProjection {
+                    name: Some(name),
+                    items: Some(vec![KeyToPath {
+                        key: "key".into(),
+                        path: path.into(),
+                        mode: None,
+                    }]),
+                    optional: None,
+                }),
+                ..Default::default()
+            };
+            let sources = [
+                vec![
+                    project(admin_secret, "admin.key"),
+                    project(rpc_secret, "rpc.key"),
+                ],
+                secrets.projected_sources.clone(),
+            ]
+            .concat();
+
+            return (
+                vec![VolumeMount {
+                    name: "secrets".into(),
+                    read_only: Some(true),
+                    mount_path: "/secrets".into(),
+                    ..Default::default()
+                }],
+                vec![Volume {
+                    name: "secrets".into(),
+                    projected: Some(ProjectedVolumeSource {
+                        default_mode: Some(0o600),
+                        sources: Some(sources),
+                    }),
+                    ..Default::default()
+                }],
+            );
+        }
+
+        let secret_volume = |name: &str, secret_name: String| Volume {
+            name: name.into(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some(secret_name),
+                default_mode: Some(0o600),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let secret_mount = |name: &str, mount_path: &str| VolumeMount {
+            name: name.into(),
+            read_only: Some(true),
+            mount_path: mount_path.into(),
+            sub_path: Some("key".into()),
+            ..Default::default()
+        };
+
+        (
+            vec![
+                secret_mount("admin-secret", "/secrets/admin.key"),
+                secret_mount("rpc-secret", "/secrets/rpc.key"),
+            ],
+            vec![
+                secret_volume("admin-secret", admin_secret),
+                secret_volume("rpc-secret", rpc_secret),
+            ],
+        )
+    }
+
     /// Optionally generates the needed secrets for this instance of a garage.
     ///
     /// Secrets can be also manually specified in the spec, which allows for the
@@ -490,8 +1222,18 @@ impl Garage {
         // Generate the secrets
         for (reference, secret_id) in needed_secrets {
             // Skip creating the secret if there is a valid entry for it in the CRD or if
-            // it was already generated
-            if reference.is_some() || secrets_handle.get_opt(&secret_id).await?.is_some() {
+            // it was already generated, making sure that generated ones are still owned by us
+            if reference.is_some() {
+                continue;
+            }
+            if let Some(existing) = secrets_handle.get_opt(&secret_id).await? {
+                let generated = existing
+                    .managed_fields()
+                    .iter()
+                    .any(|f| f.manager.as_deref() == Some("garage-operator"));
+                if generated {
+                    repair_owners(&secrets_handle, &existing, &[owner.clone()]).await?;
+                }
                 continue;
             }
 
@@ -524,7 +1266,7 @@ impl Garage {
 
         // Extract needed info from the garage
         let name = self.name_any();
-        let service_name = self.prefixed_name("api");
+        let service_name = self.service_name();
         let namespace = self
             .namespace()
             .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
@@ -545,10 +1287,12 @@ impl Garage {
             metadata: meta! {
                 owners: vec![owner],
                 name: Some(service_name.clone()),
-                labels: Some(labels! { instance: name.clone() })
+                labels: Some(self.labels(&context.garage_version))
             },
             spec: Some(ServiceSpec {
                 selector: Some(labels! { instance: name.clone() }),
+                // The admin API needs to be reachable before garage reports itself as healthy
+                publish_not_ready_addresses: Some(true),
                 ports: Some(
                     garage_services
                         .into_iter()
@@ -569,20 +1313,253 @@ impl Garage {
         };
 
         // Apply the service
-        let patch = Patch::Apply(service);
-        let params = PatchParams::apply("garage-operator");
-        services_handle
-            .patch(&service_name, &params, &patch)
-            .await?;
+        apply_if_changed(&services_handle, &service_name, service, &context.metrics).await?;
 
         Ok(())
     }
 
-    /// Return a list of capacities used by each of the specified data sources
-    pub(crate) async fn get_capacities(
+    /// Create a prometheus-operator `ServiceMonitor` for scraping the admin API, if requested
+    ///
+    /// A monitor created before it was turned off is removed again.
+    async fn create_service_monitor(&self, context: Arc<Context>) -> Result<(), Error> {
+        let client = context.client.clone();
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+        let resource = ApiResource::from_gvk(&GroupVersionKind::gvk(
+            "monitoring.coreos.com",
+            "v1",
+            "ServiceMonitor",
+        ));
+        let monitors = Api::<DynamicObject>::namespaced_with(client.clone(), &namespace, &resource);
+
+        if !self.spec.create_service_monitor {
+            // Only remove a monitor that we made, which is never there without the CRD either
+            let owned = monitors.get_opt(&name).await?.is_some_and(|monitor| {
+                monitor
+                    .owner_references()
+                    .iter()
+                    .any(|owner| Some(&owner.uid) == self.uid().as_ref())
+            });
+            if owned {
+                monitors.delete(&name, &DeleteParams::default()).await?;
+            }
+
+            return Ok(());
+        }
+
+        // Applying would fail with a confusing error if prometheus-operator is missing
+        let crds = Api::<CustomResourceDefinition>::all(client.clone());
+        if crds.get_opt(SERVICE_MONITOR_CRD).await?.is_none() {
+            return Err(Error::IllegalGarage(
+                name,
+                format!("creating a service monitor requires the {SERVICE_MONITOR_CRD} CRD"),
+            ));
+        }
+
+        // Garage serves its metrics on the admin port
+        let owner = self.controller_owner_ref(&()).unwrap();
+        // The version label of the service follows the deployed version, so only select by name
+        let selector = BTreeMap::from([("app.kubernetes.io/name", name.clone())]);
+        let mut monitor = DynamicObject::new(&name, &resource).data(json!({
+            "spec": {
+                "selector": {
+                    "matchLabels": selector,
+                },
+                "endpoints": [{
+                    "port": "admin",
+                    "path": "/metrics",
+                }],
+            },
+        }));
+        monitor.metadata.owner_references = Some(vec![owner]);
+        monitor.metadata.labels = Some(self.labels(&context.garage_version));
+
+        monitors
+            .patch(
+                &name,
+                &PatchParams::apply("garage-operator"),
+                &Patch::Apply(monitor),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Return the reason for the garage container crash looping, if it is
+    pub(crate) async fn get_crash_loop_reason(
         &self,
         context: Arc<Context>,
-    ) -> Result<Vec<ParsedQuantity>, Error> {
+    ) -> Result<Option<String>, Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+
+        // Find all of the pods backing this instance
+        let pods_handle = Api::<Pod>::namespaced(context.client.clone(), &namespace);
+        let pods = pods_handle
+            .list(&ListParams::default().labels(&format!("app.kubernetes.io/name={name}")))
+            .await?;
+
+        // Report the last termination of the first container found crash looping
+        let reason = pods
+            .into_iter()
+            .filter_map(|pod| pod.status.and_then(|s| s.container_statuses))
+            .flatten()
+            .find(|container| {
+                container
+                    .state
+                    .as_ref()
+                    .and_then(|s| s.waiting.as_ref())
+                    .and_then(|w| w.reason.as_deref())
+                    == Some("CrashLoopBackOff")
+            })
+            .map(
+                |container| match container.last_state.and_then(|s| s.terminated) {
+                    Some(terminated) => format!(
+                        "garage is crash looping: {} (exit code {}){}",
+                        terminated.reason.unwrap_or_else(|| "Unknown".into()),
+                        terminated.exit_code,
+                        terminated
+                            .message
+                            .map(|m| format!(": {m}"))
+                            .unwrap_or_default(),
+                    ),
+                    None => "garage is crash looping".into(),
+                },
+            );
+
+        Ok(reason)
+    }
+
+    /// Record a failed reconcile, marking this garage as errored once too many failed in a row
+    pub(crate) async fn record_failure(
+        &self,
+        context: Arc<Context>,
+        error: &Error,
+    ) -> Result<(), Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+
+        let status = self.status.clone().unwrap_or_default();
+        let patch = failure_status(&status, context.config.failure_threshold, error);
+        if patch["status"].get("state").is_some() {
+            let failures = status.consecutive_failures + 1;
+            warn!(r#"Garage "{namespace}/{name}" failed {failures} reconciles in a row: {error}"#);
+        }
+
+        // Merge the counts so as not to clobber the rest of the last known status
+        Api::<Garage>::namespaced(context.client.clone(), &namespace)
+            .patch_status(&name, &PatchParams::default(), &Patch::Merge(patch))
+            .await?;
+
+        Ok(())
+    }
+
+    /// The state that garage moves to once it is up, or [None] if that depends on whether the
+    /// user has laid out the cluster themselves
+    ///
+    /// A layout which is left entirely to the user is never read, so garage is ready as is.
+    fn state_once_running(&self) -> Option<GarageState> {
+        if !self.spec.manage_layout {
+            Some(GarageState::Ready)
+        } else if self.spec.auto_layout {
+            Some(GarageState::LayingOut)
+        } else {
+            None
+        }
+    }
+
+    /// The amount of zones spanned by the nodes which the operator lays out, if it manages the
+    /// layout at all
+    ///
+    /// Each garage is deployed as a single node, so its layout only ever spans one zone.
+    fn managed_zones(&self) -> Option<usize> {
+        self.spec.manage_layout.then_some(1)
+    }
+
+    /// Whether the rendered config binds the admin API to the IP of the pod
+    fn binds_to_pod_ip(&self) -> bool {
+        self.spec.config_from.is_none()
+            && self.spec.config.admin_bind_address.as_deref() == Some(POD_IP_BIND_ADDRESS)
+    }
+
+    /// Let go of the buckets of this instance which are being deleted
+    pub(crate) async fn release_buckets(&self, client: Client) -> Result<(), Error> {
+        let name = self.name_any();
+        let namespace = self.namespace();
+
+        // TODO: Listing requires filtering until `selectableFields` is stabilised and added to k8s (v1.30 and beyond)
+        let buckets = Api::<Bucket>::all(client.clone())
+            .list(&ListParams::default())
+            .await?
+            .into_iter()
+            .filter(|b| {
+                b.spec.garage_ref.name == name
+                    && Some(&b.spec.garage_ref.namespace) == namespace.as_ref()
+            });
+        for bucket in buckets {
+            bucket.release_deletion_protection(client.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether any pod of this instance is running with its admin API listening
+    pub(crate) async fn is_running(&self, context: Arc<Context>) -> Result<bool, Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+
+        let pods = Api::<Pod>::namespaced(context.client.clone(), &namespace)
+            .list(&ListParams::default().labels(&format!("app.kubernetes.io/name={name}")))
+            .await?;
+
+        Ok(pods.iter().any(pod_is_running))
+    }
+
+    /// The `topology.kubernetes.io/zone` label of the node running this instance, if any
+    pub(crate) async fn get_node_zone(
+        &self,
+        context: Arc<Context>,
+    ) -> Result<Option<String>, Error> {
+        let name = self.name_any();
+        let namespace = self
+            .namespace()
+            .ok_or_else(|| Error::IllegalGarage(name.clone(), "missing namespace".into()))?;
+
+        // Find the node that garage has been scheduled on
+        let pods_handle = Api::<Pod>::namespaced(context.client.clone(), &namespace);
+        let node_name = pods_handle
+            .list(&ListParams::default().labels(&format!("app.kubernetes.io/name={name}")))
+            .await?
+            .into_iter()
+            .find_map(|pod| pod.spec.and_then(|s| s.node_name));
+        let Some(node_name) = node_name else {
+            info!(r#"Garage "{namespace}/{name}" is not scheduled yet, using configured zone"#);
+            return Ok(None);
+        };
+
+        let node = Api::<Node>::all(context.client.clone())
+            .get_opt(&node_name)
+            .await?;
+        let zone = node.and_then(|n| n.labels().get(ZONE_LABEL).cloned());
+        if zone.is_none() {
+            info!(r#"Node "{node_name}" has no "{ZONE_LABEL}" label, using configured zone"#);
+        }
+
+        Ok(zone)
+    }
+
+    /// Return a list of capacities used by each of the specified data sources
+    ///
+    /// Capacities are ordered by the index of their mount, as given by [get_mount_for_index].
+    pub(crate) async fn get_capacities(&self, context: Arc<Context>) -> Result<Vec<i64>, Error> {
         let client = context.client.clone();
 
         let name = self.name_any();
@@ -602,16 +1579,78 @@ impl Garage {
                 .await?
                 .ok_or(Error::MissingDataSource(source.clone()))?;
 
+            // Garage stores its data in directories, so raw block volumes cannot be mounted
+            let volume_mode = info.spec.as_ref().and_then(|s| s.volume_mode.as_deref());
+            if volume_mode == Some("Block") {
+                return Err(Error::IllegalGarage(
+                    self.name_any(),
+                    format!(
+                        r#"source "{source}" is a block volume, but garage needs a filesystem"#
+                    ),
+                ));
+            }
+
             // TODO: Is this what we should do here?
-            let capacity: ParsedQuantity = info
+            let capacity = info
                 .status
-                .unwrap()
-                .capacity
-                .unwrap()
-                .into_values()
-                .map(|q| ParsedQuantity::try_from(q).unwrap())
-                .fold(ParsedQuantity::default(), |acc, cur| acc + cur);
-            info!(r#"Source "{source}" has capacity {capacity}"#);
+                .and_then(|s| s.capacity)
+                .filter(|c| !c.is_empty())
+                .map(|c| {
+                    c.values()
+                        .map(quantity_to_bytes)
+                        .try_fold(0i64, |acc, cur| {
+                            acc.checked_add(cur?)
+                                .ok_or_else(|| "capacity is too large".to_string())
+                        })
+                        .map_err(|e| {
+                            Error::IllegalGarage(
+                                self.name_any(),
+                                format!(r#"source "{source}" reports an invalid capacity: {e}"#),
+                            )
+                        })
+                })
+                .transpose()?;
+            match &capacity {
+                Some(capacity) => info!(r#"Source "{source}" has capacity {capacity}B"#),
+                None => info!(r#"Source "{source}" does not report a capacity"#),
+            }
+
+            source_info.push(capacity);
+        }
+
+        // Share the overridden capacity between the sources that can't tell us theirs
+        let unknown = source_info.iter().filter(|c| c.is_none()).count();
+        let mut source_info = if unknown == 0 {
+            source_info.into_iter().flatten().collect::<Vec<_>>()
+        } else {
+            let capacity = self.layout_capacity()?.ok_or_else(|| {
+                Error::IllegalGarage(
+                    self.name_any(),
+                    "data backings do not report a capacity, so layoutCapacity must be set".into(),
+                )
+            })?;
+            let share = capacity / unknown as i64;
+
+            source_info
+                .into_iter()
+                .map(|c| c.unwrap_or(share))
+                .collect()
+        };
+
+        // Shared data directories can't be inspected, so use their declared capacities
+        let sub_paths = self
+            .spec
+            .storage
+            .shared_data
+            .iter()
+            .flat_map(|shared| shared.paths.iter());
+        for path in sub_paths {
+            let capacity = quantity_to_bytes(&path.capacity).map_err(|e| {
+                Error::IllegalGarage(
+                    self.name_any(),
+                    format!(r#"invalid capacity for sub path "{}": {e}"#, path.sub_path),
+                )
+            })?;
 
             source_info.push(capacity);
         }
@@ -620,6 +1659,403 @@ impl Garage {
     }
 }
 
+/// Whether a garage has nothing left to do until something changes, which is the case when it
+/// stays ready or suspended
+fn settles(previous: &GarageState, next: &GarageState) -> bool {
+    previous == next && matches!(next, GarageState::Ready | GarageState::Suspended)
+}
+
+/// Render the address that the admin API listens on, listening on every address by default
+///
+/// Binding to the IP of the pod renders a placeholder, since the IP is only known once the pod
+/// has started.
+fn render_admin_bind_addr(
+    garage: &Garage,
+    address: Option<&str>,
+    port: u16,
+) -> Result<String, Error> {
+    let Some(address) = address else {
+        return Ok(format!("0.0.0.0:{port}"));
+    };
+    if address == POD_IP_BIND_ADDRESS {
+        return Ok(format!("{POD_IP_PLACEHOLDER}:{port}"));
+    }
+
+    let ip = address.parse::<IpAddr>().map_err(|_| {
+        Error::IllegalGarage(
+            garage.name_any(),
+            format!(r#"admin bind address "{address}" is not an IP address"#),
+        )
+    })?;
+    if ip.is_loopback() {
+        return Err(Error::IllegalGarage(
+            garage.name_any(),
+            format!(r#"admin bind address "{address}" is not reachable by the operator"#),
+        ));
+    }
+
+    Ok(SocketAddr::new(ip, port).to_string())
+}
+
+/// Make sure that a rendered config is valid TOML which holds the values it was rendered from
+fn check_rendered_config(garage: &Garage, rendered: &str) -> Result<(), Error> {
+    let illegal = |reason: String| {
+        Error::IllegalGarage(garage.name_any(), format!("rendered config {reason}"))
+    };
+
+    let config = rendered
+        .parse::<toml::Table>()
+        .map_err(|e| illegal(format!("is not valid TOML: {e}")))?;
+
+    // Values which break out of their strings can still produce valid TOML, so check that
+    // every interpolated value made it through as-is
+    let spec = &garage.spec.config;
+    let admin_bind_addr =
+        render_admin_bind_addr(garage, spec.admin_bind_address.as_deref(), spec.ports.admin)?;
+    let replication_mode = spec
+        .replication_factor
+        .is_none()
+        .then_some(&spec.replication_mode);
+    let expected: [(&[&str], &str, Option<&String>); 6] = [
+        (&["s3_api", "s3_region"], "region", Some(&spec.region)),
+        (
+            &["s3_api", "root_domain"],
+            "s3 api root domain",
+            spec.s3_api_root_domain.as_ref(),
+        ),
+        (&["s3_web", "index"], "web index", Some(&spec.web_index)),
+        (&["replication_mode"], "replication mode", replication_mode),
+        (
+            &["admin", "api_bind_addr"],
+            "admin bind address",
+            Some(&admin_bind_addr),
+        ),
+        (
+            &["admin", "trace_sink"],
+            "trace sink",
+            spec.trace_sink.as_ref(),
+        ),
+    ];
+    for (path, what, value) in expected {
+        let Some(value) = value else {
+            continue;
+        };
+
+        let rendered = path
+            .split_last()
+            .and_then(|(key, sections)| {
+                sections
+                    .iter()
+                    .try_fold(&config, |table, section| table.get(*section)?.as_table())?
+                    .get(*key)
+            })
+            .and_then(|rendered| rendered.as_str());
+        if rendered != Some(value.as_str()) {
+            return Err(illegal(format!(
+                r#"does not contain the {what} "{value}", it may need escaping"#
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Render user supplied options for a section of the config, one per line
+///
+/// Options are validated to be representable in TOML, and to not override any of the
+/// `reserved` options managed by the operator. Size options (see [`SIZE_OPTIONS`]) may
+/// also be specified as quantities, which are rendered as a number of bytes.
+fn render_options(
+    garage: &Garage,
+    options: &BTreeMap<String, serde_json::Value>,
+    reserved: &[&str],
+) -> Result<String, Error> {
+    let mut rendered = String::new();
+    for (key, value) in options {
+        let illegal = |reason: &str| {
+            Error::IllegalGarage(garage.name_any(), format!(r#"option "{key}" {reason}"#))
+        };
+
+        if reserved.contains(&key.as_str()) {
+            return Err(illegal("is managed by the operator"));
+        }
+        let bare_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !bare_key {
+            return Err(illegal("is not a valid key"));
+        }
+
+        // Sizes are easier to specify as quantities, but garage expects bytes
+        let value = match value {
+            serde_json::Value::String(size) if SIZE_OPTIONS.contains(&key.as_str()) => {
+                let bytes = quantity_to_bytes(&Quantity(size.clone()))
+                    .ok()
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| illegal("must be a positive size"))?;
+
+                toml::Value::Integer(bytes)
+            }
+            serde_json::Value::Number(size) if SIZE_OPTIONS.contains(&key.as_str()) => {
+                let bytes = size
+                    .as_i64()
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| illegal("must be a positive size"))?;
+
+                toml::Value::Integer(bytes)
+            }
+            value => toml::Value::try_from(value).map_err(|e| illegal(&e.to_string()))?,
+        };
+        rendered.push_str(&format!("\n{key} = {value}"));
+    }
+
+    Ok(rendered)
+}
+
+/// Render the cache size of the database engine, if one is configured
+///
+/// Only sled has a cache to size. The `lmdb_map_size` of LMDB caps how large the database may
+/// grow rather than how much memory it uses, so it is not mapped to.
+fn render_db_cache_size(config: &GarageConfig) -> Result<String, String> {
+    let Some(size) = &config.db_cache_size else {
+        return Ok(String::new());
+    };
+
+    let bytes =
+        quantity_to_bytes(size).map_err(|_| format!("invalid db cache size: {}", size.0))?;
+    match config.db_engine {
+        GarageDbEngine::Sled => Ok(format!("\nsled_cache_capacity = \"{bytes}B\"")),
+        GarageDbEngine::Lmdb => Err(
+            "db cache size is not supported by lmdb, set `lmdb_map_size` in the options to \
+             limit the size of its database instead"
+                .into(),
+        ),
+        GarageDbEngine::Sqlite => Err("db cache size is not supported by sqlite".into()),
+    }
+}
+
+/// The major version of garage, e.g. 1 for `v1.0.0`
+fn major_version(garage_version: &str) -> Option<u32> {
+    garage_version
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse().ok())
+}
+
+/// Whether a version of garage serves the v1 admin API, which is the only one the operator speaks
+///
+/// Garage only started serving it in v0.9.0. Versions which can't be parsed, such as custom
+/// tags, are assumed to be recent enough.
+fn supports_admin_api(garage_version: &str) -> bool {
+    let mut parts = garage_version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse::<u32>().ok());
+
+    !matches!(
+        (parts.next().flatten(), parts.next().flatten()),
+        (Some(0), Some(minor)) if minor < 9
+    )
+}
+
+/// Whether a version of garage configures the replication factor separately from the mode
+fn supports_replication_factor(garage_version: &str) -> bool {
+    major_version(garage_version).is_some_and(|major| major >= 1)
+}
+
+/// Whether a version of garage can limit the memory used by blocks of requests in flight
+fn supports_block_ram_buffer_max(garage_version: &str) -> bool {
+    major_version(garage_version).is_some_and(|major| major >= 1)
+}
+
+/// The consistency mode implied by a replication mode such as `3-degraded`, if not the default
+fn consistency_mode(mode: &str) -> Option<&str> {
+    mode.split_once('-')
+        .map(|(_, consistency)| consistency)
+        .filter(|consistency| matches!(*consistency, "degraded" | "dangerous"))
+}
+
+/// Validate that a replication factor agrees with the replication mode and, if known, the
+/// amount of zones that the cluster spans
+fn validate_replication(mode: &str, factor: u8, zones: Option<usize>) -> Result<(), String> {
+    if factor == 0 {
+        return Err("replication factor must be at least 1".into());
+    }
+
+    // Modes such as `3` or `3-degraded` are named after the factor they imply
+    if mode != "none" {
+        let implied = mode.split('-').next().and_then(|n| n.parse::<u8>().ok());
+        if implied != Some(factor) {
+            return Err(format!(
+                r#"replication factor {factor} conflicts with replication mode "{mode}""#
+            ));
+        }
+    }
+
+    if let Some(zones) = zones.filter(|zones| usize::from(factor) > *zones) {
+        return Err(format!(
+            "replication factor {factor} requires at least {factor} zones, but the cluster has {zones}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The CRD of prometheus-operator service monitors
+const SERVICE_MONITOR_CRD: &str = "servicemonitors.monitoring.coreos.com";
+
+/// Label holding the version of garage run by a pod
+pub const VERSION_LABEL: &str = "deuxfleurs.fr/garage-version";
+
+/// Annotation used to allow changing the region of a garage which was already configured
+///
+/// Its value must be the new region, so that it only allows the change it was set for rather
+/// than any later change as well.
+pub const ALLOW_REGION_CHANGE_ANNOTATION: &str = "deuxfleurs.fr/allow-region-change";
+
+/// Options of garage which are sizes in bytes, and may therefore be given as quantities
+///
+/// Garage has no size options in `[s3_api]`, so these are all top level options. Sizes which
+/// are managed by the operator, such as `sled_cache_capacity`, are left out. The list is
+/// documented on `GarageConfig::options`, which must be kept in sync.
+const SIZE_OPTIONS: &[&str] = &["block_size", "lmdb_map_size"];
+
+/// Top level options of the config which are managed by the operator
+const RESERVED_OPTIONS: &[&str] = &[
+    "metadata_dir",
+    "data_dir",
+    "db_engine",
+    "sled_cache_capacity",
+    "block_ram_buffer_max",
+    "replication_mode",
+    "replication_factor",
+    "consistency_mode",
+    "rpc_secret",
+    "rpc_secret_file",
+    "rpc_bind_addr",
+    "s3_api",
+    "s3_web",
+    "admin",
+];
+
+/// The well-known label holding the zone of a node
+const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+
+/// The amount of times to try fetching the admin secret before giving up
+const ADMIN_SECRET_ATTEMPTS: u32 = 4;
+
+/// Admin bind address which binds the admin API to the IP of the garage pod
+const POD_IP_BIND_ADDRESS: &str = "podIP";
+
+/// Placeholder in the rendered config which is replaced with the IP of the pod on start
+const POD_IP_PLACEHOLDER: &str = "__POD_IP__";
+
+/// Image used to fill in the IP of the pod, since the garage image has no shell
+const POD_IP_RENDER_IMAGE: &str = "busybox:1.36";
+
+/// Script filling in the IP of the pod from the config at `$1` into `$2`, wrapping IPv6
+/// addresses in brackets so that they can be followed by a port
+const POD_IP_RENDER_SCRIPT: &str =
+    r#"ip="$POD_IP"; case "$ip" in *:*) ip="[$ip]" ;; esac; sed "s/__POD_IP__/$ip/g" "$1" > "$2""#;
+
+/// The status patch recording another failed reconcile, which marks the garage as errored once
+/// `threshold` reconciles failed in a row
+fn failure_status(previous: &GarageStatus, threshold: u32, error: &Error) -> serde_json::Value {
+    let failures = previous.consecutive_failures + 1;
+    if failures >= threshold {
+        json!({
+            "status": {
+                "consecutiveFailures": failures,
+                "state": GarageState::Errored,
+                "message": error.to_string(),
+            },
+        })
+    } else {
+        json!({ "status": { "consecutiveFailures": failures } })
+    }
+}
+
+/// Build a status condition, keeping the last transition time if its status did not change
+fn condition(
+    previous: &GarageStatus,
+    generation: Option<i64>,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: String,
+) -> Condition {
+    let status = if status { "True" } else { "False" };
+    let last_transition_time = previous
+        .condition(type_)
+        .filter(|c| c.status == status)
+        .map(|c| c.last_transition_time.clone())
+        .unwrap_or_else(|| Time(Utc::now()));
+
+    Condition {
+        type_: type_.into(),
+        status: status.into(),
+        reason: reason.into(),
+        message,
+        last_transition_time,
+        observed_generation: generation,
+    }
+}
+
+/// Whether a pod is running with all of its containers started.
+///
+/// Containers are only started once their startup probe passes, which checks that the admin API
+/// is listening.
+fn pod_is_running(pod: &Pod) -> bool {
+    let Some(status) = &pod.status else {
+        return false;
+    };
+
+    status.phase.as_deref() == Some("Running")
+        && status
+            .container_statuses
+            .as_ref()
+            .is_some_and(|containers| {
+                !containers.is_empty() && containers.iter().all(|c| c.started == Some(true))
+            })
+}
+
+/// The garage CLI commands needed to layout a single instance by hand
+fn manual_layout_commands(name: &str, node_id: &str, zone: &str, capacity: i64) -> String {
+    format!(
+        "`garage layout assign -z {zone} -c {capacity}B -t garage-instance/{name} {node_id}` \
+         then `garage layout apply --version 1`"
+    )
+}
+
+/// Render the `data_dir` of the garage config from the capacities of each data directory
+///
+/// A single volume is rendered as a plain path so that garage uses all of it, unless the
+/// capacity was given explicitly by splitting a shared backing. Otherwise, every directory
+/// is listed along with its capacity.
+fn render_data_dir(capacities: &[i64], explicit: bool) -> String {
+    if let [_] = capacities {
+        if !explicit {
+            return format!(r#""{}""#, get_mount_for_index(0));
+        }
+    }
+
+    let dirs = capacities
+        .iter()
+        .enumerate()
+        .map(|(index, capacity)| {
+            format!(
+                r#"{{ path = "{}", capacity = "{}B" }}"#,
+                get_mount_for_index(index),
+                capacity,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[ {} ]", dirs.join(","))
+}
+
 // Helper for making sure that mounts line up
 fn get_mount_for_index(index: usize) -> String {
     format!("/mnt/disk{index}")
@@ -630,4 +2066,768 @@ impl Garage {
     pub fn prefixed_name(&self, rest: impl AsRef<str>) -> String {
         format!("{}-{}", self.name_any(), rest.as_ref())
     }
+
+    /// The version of garage to run for this instance, falling back to the operator default
+    pub fn version<'a>(&'a self, default_version: &'a str) -> &'a str {
+        self.spec.version.as_deref().unwrap_or(default_version)
+    }
+
+    /// The container image to run for this instance
+    ///
+    /// This must stay in sync with [`Garage::labels`] so that the version label is accurate.
+    pub fn image(&self, default_version: &str) -> String {
+        format!("dxflrs/garage:{}", self.version(default_version))
+    }
+
+    /// Labels describing the resources of this instance, including the deployed version
+    ///
+    /// Pods are labelled with [`Garage::pod_labels`] instead.
+    pub fn labels(&self, default_version: &str) -> BTreeMap<String, String> {
+        let mut labels: BTreeMap<String, String> = labels! { instance: self.name_any() };
+        labels.insert(
+            "app.kubernetes.io/version".into(),
+            self.version(default_version).to_string(),
+        );
+
+        labels
+    }
+
+    /// Labels of the pods of this instance
+    ///
+    /// These must match the selector of the deployment, which can't be changed, so the deployed
+    /// version is labelled with [`VERSION_LABEL`] rather than `app.kubernetes.io/version`.
+    pub fn pod_labels(&self, default_version: &str) -> BTreeMap<String, String> {
+        let mut labels: BTreeMap<String, String> = labels! { instance: self.name_any() };
+        labels.insert(
+            VERSION_LABEL.into(),
+            self.version(default_version).to_string(),
+        );
+
+        labels
+    }
+
+    /// The name of the service exposing all of the ports of this garage instance
+    pub fn service_name(&self) -> String {
+        self.prefixed_name("api")
+    }
+
+    /// The in-cluster URL for reaching this garage instance on the specified port
+    ///
+    /// All endpoints of garage should be derived from this so that they stay in sync
+    /// with the service created for the instance.
+    pub fn service_url(&self, port: u16) -> String {
+        format!(
+            "http://{}.{}.svc.cluster.local:{port}",
+            self.service_name(),
+            self.namespace().unwrap(),
+        )
+    }
+
+    /// Validate that the operator is able to manage the version of garage to run
+    fn validate_version(&self, default_version: &str) -> Result<(), Error> {
+        let version = self.version(default_version);
+        if !supports_admin_api(version) {
+            return Err(Error::IllegalGarage(
+                self.name_any(),
+                format!("garage {version} is not supported, the operator requires at least v0.9.0"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Validate that the storage backings can be used to run garage
+    fn validate_storage(&self) -> Result<(), Error> {
+        let storage = &self.spec.storage;
+        let sub_paths = storage.shared_data.as_ref().map_or(0, |s| s.paths.len());
+        if storage.data.is_empty() && sub_paths == 0 {
+            return Err(Error::IllegalGarage(
+                self.name_any(),
+                "storage must contain at least one data backing or shared data path".into(),
+            ));
+        }
+
+        self.layout_capacity()?;
+
+        Ok(())
+    }
+
+    /// The capacity in bytes to layout this instance with, if overridden
+    fn layout_capacity(&self) -> Result<Option<i64>, Error> {
+        let Some(capacity) = &self.spec.layout_capacity else {
+            return Ok(None);
+        };
+
+        quantity_to_bytes(capacity)
+            .ok()
+            .filter(|bytes| *bytes > 0)
+            .map(Some)
+            .ok_or_else(|| {
+                Error::IllegalGarage(
+                    self.name_any(),
+                    format!("invalid layout capacity: {}", capacity.0),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{reconcilers::ReconcileConfig, resources::GarageSpec};
+
+    #[test]
+    fn version_label_matches_image() {
+        let mut spec: GarageSpec = serde_json::from_value(json!({
+            "storage": { "meta": "meta", "data": ["data"] },
+        }))
+        .unwrap();
+
+        // Both the default and overridden versions should be reported truthfully
+        for version in ["v0.9.0", "v1.0.0"] {
+            let garage = Garage::new("garage", spec.clone());
+            let labels = garage.labels("v0.9.0");
+            let label = &labels["app.kubernetes.io/version"];
+
+            assert_eq!(label, version);
+            assert_eq!(garage.image("v0.9.0"), format!("dxflrs/garage:{label}"));
+
+            // Pods keep matching the selector of their deployment across versions
+            let pod_labels = garage.pod_labels("v0.9.0");
+            let selector: BTreeMap<String, String> = labels! { instance: garage.name_any() };
+            assert!(selector.iter().all(|(k, v)| pod_labels.get(k) == Some(v)));
+            assert_eq!(pod_labels[VERSION_LABEL], version);
+
+            spec.version = Some("v1.0.0".into());
+        }
+    }
+
+    #[test]
+    fn ready_garages_requeue_after_configured_interval() {
+        let config = ReconcileConfig::from_vars(|name| {
+            (name == "READY_REQUEUE_SECONDS").then(|| "30".to_string())
+        })
+        .unwrap();
+        let action =
+            |previous, next| config.next_action(config.ready_requeue, settles(&previous, &next));
+
+        // Both a garage which stays ready and one which just became ready wait the interval
+        let requeue = Action::requeue(Duration::from_secs(30));
+        assert_eq!(action(GarageState::Ready, GarageState::Ready), requeue);
+        assert_eq!(action(GarageState::LayingOut, GarageState::Ready), requeue);
+
+        // Only settled garages may wait for changes instead
+        let config = ReconcileConfig {
+            ready_await_change: true,
+            ..config.clone()
+        };
+        let settled = settles(&GarageState::Ready, &GarageState::Ready);
+        assert_eq!(
+            config.next_action(config.ready_requeue, settled),
+            Action::await_change()
+        );
+        assert!(!settles(&GarageState::LayingOut, &GarageState::Ready));
+    }
+
+    #[test]
+    fn manual_layout_commands_target_first_version() {
+        let commands = manual_layout_commands("garage", "abc123", "dc1", 1024);
+
+        assert!(commands.contains("garage layout assign -z dc1 -c 1024B"));
+        assert!(commands.contains("-t garage-instance/garage abc123"));
+        assert!(commands.contains("garage layout apply --version 1"));
+    }
+
+    #[test]
+    fn unmanaged_layout_is_never_read() {
+        let garage_with = |manage_layout: bool, auto_layout: bool| {
+            let spec = serde_json::from_value(json!({
+                "manageLayout": manage_layout,
+                "autoLayout": auto_layout,
+                "config": { "replicationMode": "3", "replicationFactor": 3 },
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap();
+            Garage::new("garage", spec)
+        };
+
+        // Only a managed layout without auto-layout needs to check the layout by hand
+        assert_eq!(
+            garage_with(true, true).state_once_running(),
+            Some(GarageState::LayingOut)
+        );
+        assert_eq!(garage_with(true, false).state_once_running(), None);
+        for auto_layout in [true, false] {
+            let garage = garage_with(false, auto_layout);
+            assert_eq!(garage.state_once_running(), Some(GarageState::Ready));
+        }
+
+        // The zones of a layout left to the user are unknown, so replication can't be checked
+        let render = |garage: Garage| garage.render_config_with("v1.0.0", &[1 << 30]);
+        assert!(matches!(
+            render(garage_with(true, true)),
+            Err(Error::IllegalGarage(_, _))
+        ));
+        assert!(render(garage_with(false, true)).is_ok());
+    }
+
+    #[test]
+    fn configured_zone_takes_precedence_over_node() {
+        let mut config = GarageConfig::default();
+        assert_eq!(config.zone(None), config.region);
+        assert_eq!(config.zone(Some("rack-a")), "rack-a");
+
+        config.zone = Some("dc1".into());
+        assert_eq!(config.zone(Some("rack-a")), "dc1");
+    }
+
+    #[test]
+    fn admin_api_requires_garage_v0_9() {
+        assert!(!supports_admin_api("v0.8.4"));
+        assert!(!supports_admin_api("0.7.0"));
+        assert!(supports_admin_api("v0.9.0"));
+        assert!(supports_admin_api("v1.0.0"));
+        assert!(supports_admin_api("v2.1.0"));
+        assert!(supports_admin_api("main-abc123"));
+    }
+
+    #[test]
+    fn replication_factor_requires_garage_v1() {
+        assert!(!supports_replication_factor("v0.9.4"));
+        assert!(supports_replication_factor("v1.0.0"));
+        assert!(supports_replication_factor("v2.1.0"));
+        assert!(!supports_block_ram_buffer_max("v0.9.4"));
+        assert!(supports_block_ram_buffer_max("v1.0.0"));
+    }
+
+    #[test]
+    fn impossible_replication_is_rejected() {
+        assert!(validate_replication("none", 1, Some(1)).is_ok());
+        assert!(validate_replication("3", 3, Some(3)).is_ok());
+        assert!(validate_replication("3-degraded", 3, Some(4)).is_ok());
+
+        // Zones can't be checked when the layout is managed by hand
+        assert!(validate_replication("3", 3, None).is_ok());
+
+        assert!(validate_replication("none", 0, Some(1)).is_err());
+        assert!(validate_replication("2", 3, Some(3)).is_err());
+        assert!(validate_replication("none", 3, Some(2)).is_err());
+        assert!(validate_replication("none", 0, None).is_err());
+    }
+
+    #[test]
+    fn consistency_is_kept_from_replication_mode() {
+        assert_eq!(consistency_mode("3-degraded"), Some("degraded"));
+        assert_eq!(consistency_mode("3-dangerous"), Some("dangerous"));
+        assert_eq!(consistency_mode("3"), None);
+        assert_eq!(consistency_mode("none"), None);
+    }
+
+    #[test]
+    fn pods_are_running_once_started() {
+        let pod = |phase: &str, started: &[bool]| -> Pod {
+            serde_json::from_value(json!({
+                "status": {
+                    "phase": phase,
+                    "containerStatuses": started.iter().map(|started| json!({
+                        "name": "garage",
+                        "image": "garage",
+                        "imageID": "",
+                        "ready": false,
+                        "restartCount": 0,
+                        "started": started,
+                    })).collect::<Vec<_>>(),
+                },
+            }))
+            .unwrap()
+        };
+
+        // Garage is not ready until it has a layout, so only being started matters
+        assert!(pod_is_running(&pod("Running", &[true])));
+
+        assert!(!pod_is_running(&pod("Pending", &[false])));
+        assert!(!pod_is_running(&pod("Running", &[false])));
+        assert!(!pod_is_running(&pod("Running", &[])));
+        assert!(!pod_is_running(&Pod::default()));
+    }
+
+    #[test]
+    fn garage_errors_once_failure_threshold_is_reached() {
+        let error = Error::IllegalGarage("garage".into(), "broken".into());
+        let after = |failures| GarageStatus {
+            consecutive_failures: failures,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            failure_status(&after(0), 3, &error),
+            json!({ "status": { "consecutiveFailures": 1 } })
+        );
+        assert_eq!(
+            failure_status(&after(1), 3, &error),
+            json!({ "status": { "consecutiveFailures": 2 } })
+        );
+        assert_eq!(
+            failure_status(&after(2), 3, &error),
+            json!({
+                "status": {
+                    "consecutiveFailures": 3,
+                    "state": "Errored",
+                    "message": error.to_string(),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn condition_keeps_transition_time_until_status_changes() {
+        let type_ = GarageStatus::RUNNING;
+        let unavailable = condition(&Default::default(), None, type_, false, "", "".into());
+        let previous = GarageStatus {
+            conditions: vec![unavailable.clone()],
+            ..Default::default()
+        };
+
+        let still_unavailable = condition(&previous, None, type_, false, "", "".into());
+        assert_eq!(
+            still_unavailable.last_transition_time,
+            unavailable.last_transition_time
+        );
+
+        let available = condition(&previous, None, type_, true, "", "".into());
+        assert_eq!(available.status, "True");
+        assert!(available.last_transition_time >= unavailable.last_transition_time);
+    }
+
+    #[test]
+    fn region_changes_must_be_allowed() {
+        let spec = serde_json::from_value(json!({
+            "config": { "region": "new" },
+            "storage": { "meta": "meta", "data": ["data"] },
+        }))
+        .unwrap();
+        let mut garage = Garage::new("moved", spec);
+
+        let fresh = GarageStatus::default();
+        assert_eq!(garage.region_change_blocker(&fresh), None);
+
+        let unchanged = GarageStatus {
+            region: Some("new".into()),
+            ..Default::default()
+        };
+        assert_eq!(garage.region_change_blocker(&unchanged), None);
+
+        let changed = GarageStatus {
+            region: Some("old".into()),
+            ..Default::default()
+        };
+        assert!(garage.region_change_blocker(&changed).is_some());
+
+        // Only the change to the region named by the annotation is allowed
+        for value in ["true", "old", "other"] {
+            garage
+                .annotations_mut()
+                .insert(ALLOW_REGION_CHANGE_ANNOTATION.into(), value.into());
+            assert!(garage.region_change_blocker(&changed).is_some(), "{value}");
+        }
+        garage
+            .annotations_mut()
+            .insert(ALLOW_REGION_CHANGE_ANNOTATION.into(), "new".into());
+        assert_eq!(garage.region_change_blocker(&changed), None);
+
+        // Once applied, the annotation does not allow moving on to yet another region
+        let applied = GarageStatus {
+            region: Some("new".into()),
+            ..Default::default()
+        };
+        garage.spec.config.region = "newer".into();
+        assert!(garage.region_change_blocker(&applied).is_some());
+    }
+
+    #[test]
+    fn admin_bind_address_is_validated() {
+        let garage = Garage::new(
+            "garage",
+            serde_json::from_value(json!({ "storage": { "meta": "meta", "data": ["data"] } }))
+                .unwrap(),
+        );
+        let render = |address| render_admin_bind_addr(&garage, address, 3903);
+
+        assert_eq!(render(None).unwrap(), "0.0.0.0:3903");
+        assert_eq!(render(Some("10.0.0.12")).unwrap(), "10.0.0.12:3903");
+        assert_eq!(render(Some("fd00::12")).unwrap(), "[fd00::12]:3903");
+        assert_eq!(render(Some("podIP")).unwrap(), "__POD_IP__:3903");
+
+        for address in ["127.0.0.1", "::1", "garage.local", "10.0.0.12:3903", ""] {
+            assert!(
+                render(Some(address)).is_err(),
+                "{address:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn trace_sink_is_rendered_as_given() {
+        let garage_with_sink = |sink: &str| {
+            let spec = serde_json::from_value(json!({
+                "config": { "traceSink": sink },
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap();
+            Garage::new("garage", spec)
+        };
+
+        // The URL parser drops the newline, so this would otherwise sneak in an extra key
+        let sink = "http://collector/x\"\nrpc_secret_file = \"/tmp/x";
+        let rendered = garage_with_sink(sink)
+            .render_config_with("v1.0.0", &[1 << 30])
+            .unwrap()
+            .parse::<toml::Table>()
+            .unwrap();
+        assert_eq!(rendered["admin"]["trace_sink"].as_str(), Some(sink));
+        assert_eq!(
+            rendered["rpc_secret_file"].as_str(),
+            Some("/secrets/rpc.key")
+        );
+
+        for sink in ["collector:4317", "grpc://collector:4317", "http://"] {
+            assert!(garage_with_sink(sink)
+                .render_config_with("v1.0.0", &[1 << 30])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn pod_ip_is_filled_in_on_every_start() {
+        let garage = Garage::new(
+            "garage",
+            serde_json::from_value(json!({
+                "config": { "adminBindAddress": "podIP" },
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap(),
+        );
+        assert!(garage.binds_to_pod_ip());
+
+        let bind_addr = render_admin_bind_addr(
+            &garage,
+            garage.spec.config.admin_bind_address.as_deref(),
+            3903,
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir).unwrap();
+        let (template, rendered) = (dir.join("template.toml"), dir.join("garage.toml"));
+        std::fs::write(
+            &template,
+            format!("[admin]\napi_bind_addr = \"{bind_addr}\"\n"),
+        )
+        .unwrap();
+
+        // The pod gets a new IP whenever it is rescheduled, which must end up in the config
+        for pod_ip in ["10.0.0.12", "10.0.3.7", "fd00::12"] {
+            let status = std::process::Command::new("sh")
+                .args(["-c", POD_IP_RENDER_SCRIPT, "render-config"])
+                .arg(&template)
+                .arg(&rendered)
+                .env("POD_IP", pod_ip)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            let config = std::fs::read_to_string(&rendered)
+                .unwrap()
+                .parse::<toml::Table>()
+                .unwrap();
+            let bind_addr = config["admin"]["api_bind_addr"].as_str().unwrap();
+            assert_eq!(
+                bind_addr.parse::<SocketAddr>().unwrap(),
+                SocketAddr::new(pod_ip.parse().unwrap(), 3903)
+            );
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rendered_config_must_be_valid() {
+        let garage_with_config = |config: serde_json::Value| {
+            let spec = serde_json::from_value(json!({
+                "config": config,
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap();
+            Garage::new("tricky", spec)
+        };
+        let render = |garage: &Garage| garage.render_config_with("v1.0.0", &[1 << 30]);
+
+        let plain = garage_with_config(json!({
+            "region": "us-east-1",
+            "webIndex": "home.html",
+            "s3ApiRootDomain": ".s3.example.com",
+        }));
+        let rendered = render(&plain).unwrap().parse::<toml::Table>().unwrap();
+        assert_eq!(rendered["s3_api"]["s3_region"].as_str(), Some("us-east-1"));
+        assert_eq!(
+            rendered["s3_api"]["root_domain"].as_str(),
+            Some(".s3.example.com")
+        );
+        assert_eq!(rendered["s3_web"]["index"].as_str(), Some("home.html"));
+
+        // Each of these either breaks the TOML or sneaks in extra config
+        for value in [
+            r#"quoted"value"#,
+            r#"back\slash"#,
+            r"new\nline",
+            "garage\"\nmetadata_dir = \"/tmp",
+            "garage\"\nadmin_token = \"hunter2",
+        ] {
+            for config in [
+                json!({ "region": value }),
+                json!({ "webIndex": value }),
+                json!({ "s3ApiRootDomain": format!(".{value}") }),
+                json!({ "replicationMode": value }),
+            ] {
+                let garage = garage_with_config(config.clone());
+                assert!(
+                    matches!(
+                        render(&garage),
+                        Err(Error::IllegalGarage(name, _)) if name == "tricky"
+                    ),
+                    "config {config} should be rejected"
+                );
+            }
+
+            // The trace sink is escaped, so it comes through as given
+            let sink = format!("http://collector/{value}");
+            let garage = garage_with_config(json!({ "traceSink": sink }));
+            let rendered = render(&garage).unwrap();
+            let parsed = rendered.parse::<toml::Table>().unwrap();
+            assert_eq!(parsed["admin"]["trace_sink"].as_str(), Some(sink.as_str()));
+
+            // Had it been interpolated as-is, the check would have caught it
+            let escaped = toml::Value::String(sink.clone()).to_string();
+            let raw = rendered.replace(&escaped, &format!(r#""{sink}""#));
+            assert!(
+                check_rendered_config(&garage, &raw).is_err(),
+                "trace sink {sink:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn layout_capacity_must_be_a_quantity() {
+        let garage_with_capacity = |capacity: &str| {
+            let spec = serde_json::from_value(json!({
+                "layoutCapacity": capacity,
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap();
+            Garage::new("hostpath", spec)
+        };
+
+        let garage = garage_with_capacity("10Gi");
+        assert_eq!(garage.layout_capacity().unwrap(), Some(10 << 30));
+        assert!(garage.validate_storage().is_ok());
+
+        for capacity in ["lots", "0", "-1Gi"] {
+            assert!(matches!(
+                garage_with_capacity(capacity).validate_storage(),
+                Err(Error::IllegalGarage(name, _)) if name == "hostpath"
+            ));
+        }
+    }
+
+    #[test]
+    fn single_volume_is_rendered_as_path() {
+        let gib = |n: i64| n << 30;
+
+        assert_eq!(render_data_dir(&[gib(10)], false), r#""/mnt/disk0""#);
+        assert_eq!(
+            render_data_dir(&[gib(1)], true),
+            r#"[ { path = "/mnt/disk0", capacity = "1073741824B" } ]"#
+        );
+        assert_eq!(
+            render_data_dir(&[gib(1), gib(2)], false),
+            r#"[ { path = "/mnt/disk0", capacity = "1073741824B" },{ path = "/mnt/disk1", capacity = "2147483648B" } ]"#
+        );
+
+        // Both forms must be valid TOML for garage to start
+        for data_dir in [
+            render_data_dir(&[gib(1)], false),
+            render_data_dir(&[gib(1), gib(2)], false),
+        ] {
+            assert!(format!("data_dir = {data_dir}")
+                .parse::<toml::Table>()
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn projected_secrets_keep_their_paths() {
+        let spec = serde_json::from_value(json!({
+            "secrets": {
+                "projected": true,
+                "projectedSources": [{ "configMap": { "name": "extra" } }],
+            },
+            "storage": { "meta": "meta", "data": ["data"] },
+        }))
+        .unwrap();
+        let garage = Garage::new("projected", spec);
+
+        let (mounts, volumes) = garage.secret_volumes();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].mount_path, "/secrets");
+        assert_eq!(mounts[0].sub_path, None);
+
+        let sources = volumes[0]
+            .projected
+            .as_ref()
+            .and_then(|p| p.sources.clone())
+            .unwrap();
+        let paths = sources
+            .iter()
+            .filter_map(|s| s.secret.as_ref())
+            .map(|s| {
+                let item = &s.items.as_ref().unwrap()[0];
+                (s.name.clone().unwrap(), item.key.clone(), item.path.clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                (
+                    "projected-admin.key".into(),
+                    "key".into(),
+                    "admin.key".into()
+                ),
+                ("projected-rpc.key".into(), "key".into(), "rpc.key".into()),
+            ]
+        );
+        assert!(sources.iter().any(|s| s.config_map.is_some()));
+
+        // Plain secret mounts stay the default
+        let garage = Garage {
+            spec: GarageSpec {
+                secrets: Default::default(),
+                ..garage.spec
+            },
+            ..garage
+        };
+        let (mounts, _) = garage.secret_volumes();
+        let paths = mounts
+            .iter()
+            .map(|m| m.mount_path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/secrets/admin.key", "/secrets/rpc.key"]);
+    }
+
+    #[test]
+    fn top_level_options_are_validated() {
+        let garage = Garage::new(
+            "tuned",
+            serde_json::from_value(json!({
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap(),
+        );
+        let options = |options: serde_json::Value| serde_json::from_value(options).unwrap();
+
+        let rendered = render_options(
+            &garage,
+            &options(json!({ "block_size": "4Mi", "compression_level": 3 })),
+            RESERVED_OPTIONS,
+        )
+        .unwrap();
+        assert_eq!(rendered, "\nblock_size = 4194304\ncompression_level = 3");
+
+        // Only known size options are converted, anything else is passed through as is, even
+        // if it looks like a size
+        let rendered = render_options(
+            &garage,
+            &options(json!({ "block_size": "1Mi", "max_body_size": "1Mi" })),
+            RESERVED_OPTIONS,
+        )
+        .unwrap();
+        assert_eq!(rendered, "\nblock_size = 1048576\nmax_body_size = \"1Mi\"");
+        for invalid in [json!({ "block_size": "0" }), json!({ "block_size": "big" })] {
+            assert!(render_options(&garage, &options(invalid), RESERVED_OPTIONS).is_err());
+        }
+
+        for reserved in [json!({ "data_dir": "/tmp" }), json!({ "s3_api": {} })] {
+            assert!(matches!(
+                render_options(&garage, &options(reserved), RESERVED_OPTIONS),
+                Err(Error::IllegalGarage(name, _)) if name == "tuned"
+            ));
+        }
+    }
+
+    #[test]
+    fn db_cache_size_only_maps_to_sled_cache() {
+        let config = |engine: GarageDbEngine, size: Option<&str>| GarageConfig {
+            db_engine: engine,
+            db_cache_size: size.map(|size| Quantity(size.into())),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            render_db_cache_size(&config(GarageDbEngine::Sled, Some("64Mi"))).unwrap(),
+            "\nsled_cache_capacity = \"67108864B\""
+        );
+        assert!(render_db_cache_size(&config(GarageDbEngine::Sled, Some("lots"))).is_err());
+
+        // The map size of LMDB is a limit on the database rather than a cache
+        assert!(render_db_cache_size(&config(GarageDbEngine::Lmdb, Some("64Mi"))).is_err());
+        assert!(render_db_cache_size(&config(GarageDbEngine::Sqlite, Some("64Mi"))).is_err());
+        for engine in [
+            GarageDbEngine::Lmdb,
+            GarageDbEngine::Sled,
+            GarageDbEngine::Sqlite,
+        ] {
+            assert_eq!(render_db_cache_size(&config(engine, None)).unwrap(), "");
+        }
+    }
+
+    #[test]
+    fn empty_data_is_rejected() {
+        let spec = serde_json::from_value(json!({
+            "storage": { "meta": "meta", "data": [] },
+        }))
+        .unwrap();
+        let garage = Garage::new("empty", spec);
+
+        assert!(matches!(
+            garage.validate_storage(),
+            Err(Error::IllegalGarage(name, _)) if name == "empty"
+        ));
+    }
+
+    #[test]
+    fn shared_data_paths_count_as_data() {
+        let spec = serde_json::from_value(json!({
+            "storage": {
+                "meta": "meta",
+                "sharedData": {
+                    "claim": "data",
+                    "paths": [{ "subPath": "a", "capacity": "1Gi" }],
+                },
+            },
+        }))
+        .unwrap();
+        let garage = Garage::new("shared", spec);
+        assert!(garage.validate_storage().is_ok());
+
+        let mut garage = garage;
+        garage
+            .spec
+            .storage
+            .shared_data
+            .as_mut()
+            .unwrap()
+            .paths
+            .clear();
+        assert!(garage.validate_storage().is_err());
+    }
 }
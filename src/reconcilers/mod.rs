@@ -1,10 +1,24 @@
-use std::sync::Arc;
+use std::{env, fmt::Debug, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use kube::{runtime::controller::Action, Client, CustomResourceExt, ResourceExt};
+use chrono::{DateTime, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::controller::Action,
+    Api, Client, CustomResourceExt, Resource, ResourceExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
 use tokio::sync::RwLock;
+use tracing::info;
 
-use crate::{operator::Diagnostics, Error, Metrics};
+use crate::{
+    admin_api::AdminClients,
+    operator::Diagnostics,
+    resources::{Garage, NamespacedReference},
+    Error, Metrics,
+};
 
 pub mod access_key;
 pub mod bucket;
@@ -23,6 +37,283 @@ pub struct CommonContext {
 
     /// The version of garage in use
     pub garage_version: String,
+
+    /// Tunables for how resources are reconciled
+    pub config: ReconcileConfig,
+
+    /// HTTP clients for the admin APIs of garages, kept around to reuse their connections
+    pub(crate) admin_clients: AdminClients,
+}
+
+/// Tunables for how resources are reconciled
+#[derive(Clone, Debug)]
+pub struct ReconcileConfig {
+    /// How long to wait before resyncing a resource which is ready.
+    ///
+    /// Lower values pick up out-of-band changes faster, at the cost of increased load on the
+    /// garage admin API. Set with `READY_REQUEUE_SECONDS`, which must be at least 1.
+    pub ready_requeue: Duration,
+
+    /// The amount of resources to fetch per request when listing watched resources.
+    ///
+    /// Larger pages mean fewer round trips on clusters with many resources, at the cost of
+    /// larger responses. Set with `WATCH_PAGE_SIZE`.
+    pub watch_page_size: u32,
+
+    /// Whether or not lists of watched resources may be served from the API server's cache.
+    ///
+    /// This greatly reduces the load on etcd, but the initial list may be slightly stale,
+    /// which is fine since resources are requeued regularly. Set with `WATCH_ANY_SEMANTIC`.
+    pub watch_any_semantic: bool,
+
+    /// The amount of reconciles of a garage which may fail in a row before it is marked as
+    /// errored, which starts it over from scratch. Set with `FAILURE_THRESHOLD`.
+    pub failure_threshold: u32,
+
+    /// How long a garage may be pending deletion before failures to clean it up are ignored,
+    /// and how long a bucket still used by access keys may be kept from being deleted.
+    ///
+    /// Without this, a cleanup which keeps failing would leave the resource stuck terminating
+    /// until its finalizer is removed by hand. Set with `CLEANUP_TIMEOUT_SECONDS`.
+    pub cleanup_timeout: Duration,
+
+    /// Whether to wait for changes to garages which stay ready, instead of resyncing them
+    /// after `ready_requeue`.
+    ///
+    /// Changes to garages, their deployments, buckets, and access keys are still picked up by
+    /// the watches, but other changes, such as to generated secrets or within garage itself,
+    /// are only noticed on the next `resync`. Set with `READY_AWAIT_CHANGE`.
+    pub ready_await_change: bool,
+
+    /// How often to reconcile every garage regardless of its state, as a fallback for changes
+    /// which the watches cannot see.
+    ///
+    /// Disabled by default. Set with `RESYNC_SECONDS`.
+    pub resync: Option<Duration>,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            ready_requeue: Duration::from_secs(60 * 60),
+            watch_page_size: 50,
+            watch_any_semantic: true,
+            failure_threshold: 5,
+            cleanup_timeout: Duration::from_secs(5 * 60),
+            ready_await_change: false,
+            resync: None,
+        }
+    }
+}
+
+impl ReconcileConfig {
+    /// Load the config from the environment, using the defaults for anything not set
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Load the config from variables looked up by name, using the defaults for anything not set
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, anyhow::Error> {
+        let mut config = Self::default();
+
+        if let Some(seconds) = var("READY_REQUEUE_SECONDS") {
+            // Requeueing right away would reconcile ready resources in a busy loop
+            config.ready_requeue = match seconds.parse()? {
+                0 => anyhow::bail!("READY_REQUEUE_SECONDS must be at least 1"),
+                seconds => Duration::from_secs(seconds),
+            };
+        }
+        if let Some(page_size) = var("WATCH_PAGE_SIZE") {
+            config.watch_page_size = page_size.parse()?;
+        }
+        if let Some(any_semantic) = var("WATCH_ANY_SEMANTIC") {
+            config.watch_any_semantic = any_semantic.parse()?;
+        }
+        if let Some(threshold) = var("FAILURE_THRESHOLD") {
+            config.failure_threshold = threshold.parse()?;
+        }
+        if let Some(seconds) = var("CLEANUP_TIMEOUT_SECONDS") {
+            config.cleanup_timeout = Duration::from_secs(seconds.parse()?);
+        }
+        if let Some(await_change) = var("READY_AWAIT_CHANGE") {
+            config.ready_await_change = await_change.parse()?;
+        }
+        if let Some(seconds) = var("RESYNC_SECONDS") {
+            config.resync = Some(Duration::from_secs(seconds.parse()?));
+        }
+
+        Ok(config)
+    }
+
+    /// What to do once a resource was reconciled, given whether it settled in a ready state
+    pub fn next_action(&self, requeue: Duration, settled: bool) -> Action {
+        if settled && self.ready_await_change {
+            Action::await_change()
+        } else {
+            Action::requeue(requeue)
+        }
+    }
+}
+
+/// Whether a resource has been pending deletion for longer than the cleanup timeout
+pub(crate) fn cleanup_expired(
+    deleted_at: Option<DateTime<Utc>>,
+    timeout: Duration,
+    now: DateTime<Utc>,
+) -> bool {
+    let Ok(timeout) = chrono::Duration::from_std(timeout) else {
+        return false;
+    };
+
+    deleted_at.is_some_and(|deleted_at| now - deleted_at >= timeout)
+}
+
+/// The error for a resource referencing a garage which is not among the given garages
+///
+/// Resources of a missing garage are never reconciled by it, so garage passes flag them instead.
+pub(crate) fn missing_garage(
+    garage_ref: &NamespacedReference,
+    garages: &[Garage],
+) -> Option<Error> {
+    let exists = garages.iter().any(|g| {
+        g.name_any() == garage_ref.name && g.namespace().as_ref() == Some(&garage_ref.namespace)
+    });
+
+    (!exists).then(|| Error::MissingGarage(format!("{}/{}", garage_ref.namespace, garage_ref.name)))
+}
+
+/// Apply a managed resource, skipping the patch if the live resource already matches it
+///
+/// The live resource is compared rather than what was last applied, so that changes made by
+/// anything else, such as `kubectl edit`, are reverted on the next pass.
+pub(crate) async fn apply_if_changed<K>(
+    api: &Api<K>,
+    name: &str,
+    resource: K,
+    metrics: &Metrics,
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()> + Clone + Debug + Serialize + DeserializeOwned,
+{
+    let existing = api.get_opt(name).await?;
+    if let Some(existing) = existing {
+        let intended = serde_json::to_value(&resource).map_err(Error::SerializationError)?;
+        let live = serde_json::to_value(&existing).map_err(Error::SerializationError)?;
+        if matches_live(&intended, &live) {
+            metrics.patch_skipped(K::kind(&()).as_ref());
+            return Ok(());
+        }
+    }
+
+    api.patch(
+        name,
+        &PatchParams::apply("garage-operator"),
+        &Patch::Apply(resource),
+    )
+    .await?;
+    metrics.patch_applied(K::kind(&()).as_ref());
+
+    Ok(())
+}
+
+/// Add any of the given owners which are missing from an existing resource
+///
+/// Garbage collection relies on owner references, so they are put back if something
+/// stripped them. Other owners of the resource are kept as-is.
+pub(crate) async fn repair_owners<K>(
+    api: &Api<K>,
+    existing: &K,
+    owners: &[OwnerReference],
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()> + Clone + Debug + DeserializeOwned,
+{
+    let Some(patch) = owners_patch(existing, owners) else {
+        return Ok(());
+    };
+
+    let name = existing.name_any();
+    info!("Restoring owner references of {} {name}", K::kind(&()));
+
+    // Owner references are merged by UID, so applying only the missing ones under their own
+    // field manager leaves the other owners and the fields of the resource alone
+    api.patch(
+        &name,
+        &PatchParams::apply("garage-operator-owners"),
+        &Patch::Apply(patch),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// The apply patch restoring the owners which are missing from an existing resource, if any
+fn owners_patch<K>(existing: &K, owners: &[OwnerReference]) -> Option<Value>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let missing = missing_owners(existing.owner_references(), owners);
+    if missing.is_empty() {
+        return None;
+    }
+
+    Some(json!({
+        "apiVersion": K::api_version(&()),
+        "kind": K::kind(&()),
+        "metadata": {
+            "name": existing.name_any(),
+            "ownerReferences": missing,
+        },
+    }))
+}
+
+/// Whether every field set on an intended resource has the same value in the live resource
+///
+/// Fields which are only set on the live resource, e.g. defaults filled in by the API server or
+/// fields managed by others, are ignored. Lists must match item by item, so that anything
+/// added to them is reverted as well.
+fn matches_live(intended: &Value, live: &Value) -> bool {
+    match (intended, live) {
+        (Value::Object(intended), Value::Object(live)) => {
+            intended.iter().all(|(key, value)| match live.get(key) {
+                Some(live) => matches_live(value, live),
+                None => is_empty(value),
+            })
+        }
+        (Value::Array(intended), Value::Array(live)) => {
+            intended.len() == live.len()
+                && intended.iter().zip(live).all(|(i, l)| matches_live(i, l))
+        }
+        (intended, Value::Null) => is_empty(intended),
+        (intended, live) => intended == live,
+    }
+}
+
+/// Whether a value is left out of resources returned by the API server
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+/// The owners which are wanted but not referenced by a resource
+///
+/// A resource can only have a single controller, so restored owners don't claim to be its
+/// controller if another owner already is.
+fn missing_owners(existing: &[OwnerReference], wanted: &[OwnerReference]) -> Vec<OwnerReference> {
+    let controlled = existing.iter().any(|e| e.controller == Some(true));
+
+    wanted
+        .iter()
+        .filter(|w| !existing.iter().any(|e| e.uid == w.uid))
+        .map(|w| OwnerReference {
+            controller: w.controller.filter(|_| !controlled),
+            ..w.clone()
+        })
+        .collect()
 }
 
 /// A resource that can be reconciled by a controller
@@ -39,3 +330,192 @@ where
     /// Attempt to deploy all necessary sub-resources for this CRD.
     async fn deploy_resources(&self, context: Arc<Self::Context>) -> Result<(), Error>;
 }
+
+#[cfg(test)]
+mod test {
+    use k8s_openapi::{api::core::v1::Secret, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+    use kube::runtime::controller::Action;
+
+    use super::*;
+
+    #[test]
+    fn references_to_other_garages_are_missing() {
+        let garage = |name: &str, namespace: &str| {
+            let spec = serde_json::from_value(json!({
+                "storage": { "meta": "meta", "data": ["data"] },
+            }))
+            .unwrap();
+            let mut garage = Garage::new(name, spec);
+            garage.meta_mut().namespace = Some(namespace.into());
+            garage
+        };
+        let garage_ref = |name: &str, namespace: &str| NamespacedReference {
+            name: name.into(),
+            namespace: namespace.into(),
+        };
+        let garages = [garage("garage", "default"), garage("other", "storage")];
+
+        assert!(missing_garage(&garage_ref("garage", "default"), &garages).is_none());
+        assert!(missing_garage(&garage_ref("other", "storage"), &garages).is_none());
+
+        // Both the name and the namespace have to match
+        for (name, namespace) in [("garage", "storage"), ("missing", "default")] {
+            assert!(matches!(
+                missing_garage(&garage_ref(name, namespace), &garages),
+                Some(Error::MissingGarage(garage)) if garage == format!("{namespace}/{name}")
+            ));
+        }
+        assert!(missing_garage(&garage_ref("garage", "default"), &[]).is_some());
+    }
+
+    #[test]
+    fn stripped_owners_are_missing() {
+        let owner = |uid: &str| OwnerReference {
+            api_version: "deuxfleurs.fr/v0alpha".into(),
+            kind: "Garage".into(),
+            name: "garage".into(),
+            uid: uid.into(),
+            ..Default::default()
+        };
+        let wanted = [owner("garage")];
+
+        // Stripping the owner reference should be noticed
+        let stripped = [owner("other")];
+        assert_eq!(missing_owners(&stripped, &wanted), wanted);
+        assert_eq!(missing_owners(&[], &wanted), wanted);
+
+        // Once restored alongside the other owner, nothing is missing anymore
+        let restored = [stripped.as_slice(), &missing_owners(&stripped, &wanted)].concat();
+        assert!(missing_owners(&restored, &wanted).is_empty());
+    }
+
+    #[test]
+    fn restoring_owners_only_applies_missing_ones() {
+        let owner = |uid: &str, controller: bool| OwnerReference {
+            api_version: "deuxfleurs.fr/v0alpha".into(),
+            kind: "Garage".into(),
+            name: "garage".into(),
+            uid: uid.into(),
+            controller: Some(controller),
+            ..Default::default()
+        };
+        let secret = |owners: Vec<OwnerReference>| Secret {
+            metadata: ObjectMeta {
+                name: Some("garage.admin.key".into()),
+                owner_references: Some(owners),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let wanted = [owner("garage", true)];
+
+        // Nothing is patched while the owner is still there
+        let owned = secret(vec![owner("other", false), owner("garage", true)]);
+        assert_eq!(owners_patch(&owned, &wanted), None);
+
+        // Only the stripped owner is applied, so other owners and fields are left alone
+        let stripped = secret(vec![owner("other", false)]);
+        assert_eq!(
+            owners_patch(&stripped, &wanted),
+            Some(json!({
+                "apiVersion": "v1",
+                "kind": "Secret",
+                "metadata": {
+                    "name": "garage.admin.key",
+                    "ownerReferences": [{
+                        "apiVersion": "deuxfleurs.fr/v0alpha",
+                        "kind": "Garage",
+                        "name": "garage",
+                        "uid": "garage",
+                        "controller": true,
+                    }],
+                },
+            }))
+        );
+
+        // The API server rejects a second controller, so the restored owner is a plain one
+        let controlled = secret(vec![owner("other", true)]);
+        let patch = owners_patch(&controlled, &wanted).unwrap();
+        let restored = &patch["metadata"]["ownerReferences"][0];
+        assert_eq!(restored["uid"], "garage");
+        assert!(restored.get("controller").is_none());
+    }
+
+    #[test]
+    fn drift_from_intended_fields_is_noticed() {
+        let intended = json!({
+            "metadata": { "name": "garage", "labels": { "app": "garage" }, "annotations": {} },
+            "spec": { "replicas": 1, "args": ["server"], "selector": null },
+        });
+
+        // Defaults and fields managed by others don't count as drift
+        let live = json!({
+            "metadata": {
+                "name": "garage",
+                "labels": { "app": "garage", "team": "storage" },
+                "resourceVersion": "12",
+            },
+            "spec": { "replicas": 1, "args": ["server"], "revisionHistoryLimit": 10 },
+        });
+        assert!(matches_live(&intended, &live));
+
+        // Changing or adding to anything that is managed does
+        let mut edited = live.clone();
+        edited["spec"]["replicas"] = json!(0);
+        assert!(!matches_live(&intended, &edited));
+
+        let mut edited = live.clone();
+        edited["spec"]["args"] = json!(["server", "--debug"]);
+        assert!(!matches_live(&intended, &edited));
+
+        let mut edited = live;
+        edited["metadata"]["labels"] = json!({ "team": "storage" });
+        assert!(!matches_live(&intended, &edited));
+    }
+
+    #[test]
+    fn cleanup_expires_after_timeout() {
+        let timeout = Duration::from_secs(60);
+        let deleted_at = Utc::now();
+        let after = |seconds| deleted_at + chrono::Duration::seconds(seconds);
+
+        assert!(!cleanup_expired(None, timeout, after(120)));
+        assert!(!cleanup_expired(Some(deleted_at), timeout, after(30)));
+        assert!(cleanup_expired(Some(deleted_at), timeout, after(60)));
+        assert!(cleanup_expired(Some(deleted_at), timeout, after(120)));
+    }
+
+    #[test]
+    fn settled_resources_can_await_changes() {
+        let requeue = Duration::from_secs(60);
+        let config = ReconcileConfig::default();
+        assert_eq!(config.next_action(requeue, true), Action::requeue(requeue));
+
+        let config = ReconcileConfig {
+            ready_await_change: true,
+            ..config
+        };
+        assert_eq!(config.next_action(requeue, true), Action::await_change());
+        assert_eq!(config.next_action(requeue, false), Action::requeue(requeue));
+    }
+
+    #[test]
+    fn ready_requeue_is_configurable() {
+        let ready_requeue = |value: Option<&str>| {
+            ReconcileConfig::from_vars(|name| {
+                value
+                    .filter(|_| name == "READY_REQUEUE_SECONDS")
+                    .map(Into::into)
+            })
+            .map(|config| config.ready_requeue)
+        };
+
+        assert_eq!(ready_requeue(None).unwrap(), Duration::from_secs(60 * 60));
+        assert_eq!(ready_requeue(Some("30")).unwrap(), Duration::from_secs(30));
+
+        // Requeueing without any delay, or with a nonsensical one, is rejected
+        for invalid in ["0", "-5", "soon", ""] {
+            assert!(ready_requeue(Some(invalid)).is_err(), "{invalid}");
+        }
+    }
+}
@@ -34,6 +34,21 @@ pub struct AccessKeySpec {
 
     /// Set the location of the generated secret.
     pub secret_ref: SecretReference,
+
+    /// Whether or not to write the credentials of the key to a secret.
+    ///
+    /// Disable this when managing credentials externally. The access key ID is still exposed
+    /// in the status, while the secret key must be fetched from garage directly, e.g. with
+    /// `garage key info --show-secret`.
+    #[serde(default = "defaults::write_secret")]
+    pub write_secret: bool,
+
+    /// Whether or not to write the non-sensitive connection info of the key to a config map.
+    ///
+    /// The config map shares the name of the secret and holds the endpoint, region, bucket
+    /// name, and access key ID, leaving only the secret key in the secret.
+    #[serde(default)]
+    pub write_config_map: bool,
 }
 
 /// The required permissions for this access key
@@ -47,7 +62,27 @@ pub struct AccessKeyPermissions {
     pub write: bool,
 
     /// Allow modifying the configuration of a bucket.
+    ///
+    /// Owners are always granted read and write access as well.
     pub owner: bool,
+
+    /// Shortcut for granting read, write, and owner access to a bucket.
+    pub all: bool,
+}
+
+impl AccessKeyPermissions {
+    /// The permissions to actually apply to a bucket, after resolving shortcuts
+    /// and implied permissions.
+    pub fn effective(&self) -> Self {
+        let owner = self.owner || self.all;
+
+        Self {
+            read: self.read || owner,
+            write: self.write || owner,
+            owner,
+            all: self.all,
+        }
+    }
 }
 
 /// The status of an access key.
@@ -65,6 +100,22 @@ pub struct AccessKeyStatus {
     /// Format is RWO, where R is read, W is write, and O is owner. Missing permissions
     /// show as -.
     pub permissions_friendly: String,
+
+    /// The permissions last requested from garage, in the same format as
+    /// `permissionsFriendly`.
+    ///
+    /// Garage may report different permissions than requested, e.g. when they were changed
+    /// outside of the operator, so changes to the spec are detected against this instead.
+    #[serde(default)]
+    pub requested_permissions: String,
+
+    /// A human readable message describing the current state, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+
+    /// The generation of the spec which was last reconciled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
 }
 
 /// The possible states of an access key
@@ -91,3 +142,54 @@ impl Display for AccessKeyPermissions {
         write!(f, "{}", if self.owner { 'O' } else { '-' })
     }
 }
+
+mod defaults {
+    pub fn write_secret() -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secrets_are_written_by_default() {
+        let spec: AccessKeySpec = serde_json::from_value(serde_json::json!({
+            "garageRef": { "name": "garage", "namespace": "default" },
+            "bucketRef": { "name": "bucket", "namespace": "default" },
+            "permissions": {},
+            "secretRef": { "name": "secret" },
+        }))
+        .unwrap();
+
+        assert!(spec.write_secret);
+        assert!(!spec.write_config_map);
+    }
+
+    #[test]
+    fn owner_implies_read_and_write() {
+        let permissions = AccessKeyPermissions {
+            owner: true,
+            ..Default::default()
+        };
+
+        let effective = permissions.effective();
+        assert!(effective.read && effective.write && effective.owner);
+        assert_eq!(effective.to_string(), "RWO");
+    }
+
+    #[test]
+    fn all_grants_everything() {
+        let permissions = AccessKeyPermissions {
+            all: true,
+            ..Default::default()
+        };
+
+        assert_eq!(permissions.effective().to_string(), "RWO");
+        assert_eq!(
+            AccessKeyPermissions::default().effective().to_string(),
+            "---"
+        );
+    }
+}
@@ -15,6 +15,7 @@ use super::NamespacedReference;
     namespaced,
     printcolumn = r#"{ "name": "garage", "type": "string", "description": "owning garage instance", "jsonPath": ".spec.garageRef" }"#,
     printcolumn = r#"{ "name": "quotas", "type": "string", "description": "quotas for this bucket", "jsonPath": ".spec.quotas" }"#,
+    printcolumn = r#"{ "name": "usage", "type": "string", "description": "usage relative to quotas", "jsonPath": ".status.quotaState" }"#,
     printcolumn = r#"{ "name": "status", "type": "string", "description": "bucket status", "jsonPath": ".status.state" }"#
 )]
 #[serde(rename_all = "camelCase")]
@@ -25,6 +26,16 @@ pub struct BucketSpec {
     /// Quotas for this bucket.
     #[serde(default)]
     pub quotas: BucketQuotas,
+
+    /// Website hosting configuration for this bucket.
+    #[serde(default)]
+    pub website: BucketWebsite,
+
+    /// Whether or not to revoke access for any key not managed by an `AccessKey` for this bucket.
+    ///
+    /// This is destructive for keys created outside of the operator, so it is disabled by default.
+    #[serde(default)]
+    pub enforce_key_allowlist: bool,
 }
 
 /// Quotas for a bucket.
@@ -32,10 +43,88 @@ pub struct BucketSpec {
 #[serde(default, rename_all = "camelCase")]
 pub struct BucketQuotas {
     /// The max size any single file.
+    ///
+    /// Either a quantity, such as `10Gi`, or a percentage of the capacity of the garage, such
+    /// as `10%`. Percentages must be above 0% and at most 100%, and follow the capacity of the
+    /// garage as it changes.
     pub max_size: Option<Quantity>,
 
     /// The maximum amount of objects allowed.
-    pub max_object_count: Option<usize>,
+    ///
+    /// Garage stores this as a signed 64 bit integer, so it may not exceed 9223372036854775807.
+    pub max_object_count: Option<u64>,
+
+    /// The percentage of any quota after which the bucket is reported as nearly full.
+    ///
+    /// Defaults to 90, and must be between 1 and 100.
+    #[schemars(range(min = 1, max = 100))]
+    pub nearly_full_percent: Option<u8>,
+}
+
+impl BucketQuotas {
+    /// Check that the quotas can be used for determining the usage state of a bucket
+    pub fn validate(&self) -> Result<(), String> {
+        match self.nearly_full_percent {
+            Some(percent) if !(1..=100).contains(&percent) => Err(format!(
+                "nearly full percent {percent} must be between 1 and 100"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Determine the quota state of a bucket given its current usage in bytes and objects
+    pub fn usage_state(&self, max_size: Option<i64>, bytes: i64, objects: i64) -> BucketQuotaState {
+        let nearly_full = f64::from(self.nearly_full_percent.unwrap_or(90)) / 100.0;
+
+        // Find the most used quota, if any
+        let usage = [
+            max_size.map(|max| bytes as f64 / max as f64),
+            self.max_object_count.map(|max| objects as f64 / max as f64),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(0.0, f64::max);
+
+        if usage >= 1.0 {
+            BucketQuotaState::Exceeded
+        } else if usage >= nearly_full {
+            BucketQuotaState::NearlyFull
+        } else {
+            BucketQuotaState::Ok
+        }
+    }
+}
+
+/// The state of a bucket's usage relative to its quotas
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+pub enum BucketQuotaState {
+    /// The bucket is within its quotas.
+    #[default]
+    Ok,
+
+    /// The bucket is close to exceeding one of its quotas.
+    NearlyFull,
+
+    /// The bucket has reached one of its quotas, so writes will fail.
+    Exceeded,
+}
+
+/// Website hosting configuration for a bucket.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BucketWebsite {
+    /// Whether or not to serve this bucket as a website.
+    pub enabled: bool,
+
+    /// The document to serve when requesting a directory.
+    ///
+    /// Defaults to the `webIndex` of the owning garage.
+    pub index_document: Option<String>,
+
+    /// The document to serve when an object could not be found.
+    ///
+    /// Defaults to the `webErrorDocument` of the owning garage.
+    pub error_document: Option<String>,
 }
 
 /// The possible states of a bucket
@@ -57,10 +146,99 @@ pub enum BucketState {
 
 /// The status of a bucket
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct BucketStatus {
     /// The garage internal ID for this bucket
     pub id: String,
 
     /// The state of the bucket
     pub state: BucketState,
+
+    /// Whether this resource took over a bucket which already existed in garage
+    #[serde(default)]
+    pub adopted: bool,
+
+    /// The usage of the bucket relative to its quotas, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_state: Option<BucketQuotaState>,
+
+    /// A human readable message describing the current state, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+
+    /// The garage which this bucket was last reconciled against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub garage_ref: Option<NamespacedReference>,
+
+    /// The generation of the spec which was last reconciled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usage_is_compared_to_every_quota() {
+        let quotas = BucketQuotas {
+            max_object_count: Some(100),
+            ..Default::default()
+        };
+
+        // Without quotas, a bucket can never be full
+        let unlimited = BucketQuotas::default();
+        assert_eq!(
+            unlimited.usage_state(None, i64::MAX, i64::MAX),
+            BucketQuotaState::Ok
+        );
+
+        // Bytes are checked against the resolved size quota
+        let state = |bytes| unlimited.usage_state(Some(1000), bytes, 0);
+        assert_eq!(state(0), BucketQuotaState::Ok);
+        assert_eq!(state(899), BucketQuotaState::Ok);
+        assert_eq!(state(900), BucketQuotaState::NearlyFull);
+        assert_eq!(state(1000), BucketQuotaState::Exceeded);
+
+        // Objects are checked against the object quota
+        let state = |objects| quotas.usage_state(None, 0, objects);
+        assert_eq!(state(0), BucketQuotaState::Ok);
+        assert_eq!(state(89), BucketQuotaState::Ok);
+        assert_eq!(state(90), BucketQuotaState::NearlyFull);
+        assert_eq!(state(101), BucketQuotaState::Exceeded);
+
+        // The most used quota wins
+        assert_eq!(
+            quotas.usage_state(Some(1000), 950, 10),
+            BucketQuotaState::NearlyFull
+        );
+        assert_eq!(
+            quotas.usage_state(Some(1000), 10, 100),
+            BucketQuotaState::Exceeded
+        );
+    }
+
+    #[test]
+    fn nearly_full_percent_is_configurable() {
+        let quotas = |percent| BucketQuotas {
+            max_object_count: Some(100),
+            nearly_full_percent: Some(percent),
+            ..Default::default()
+        };
+
+        assert_eq!(quotas(50).usage_state(None, 0, 49), BucketQuotaState::Ok);
+        assert_eq!(
+            quotas(50).usage_state(None, 0, 50),
+            BucketQuotaState::NearlyFull
+        );
+        assert_eq!(quotas(100).usage_state(None, 0, 99), BucketQuotaState::Ok);
+
+        // Empty buckets would be nearly full at 0%, and full ones never at above 100%
+        assert!(quotas(1).validate().is_ok());
+        assert!(quotas(100).validate().is_ok());
+        assert!(BucketQuotas::default().validate().is_ok());
+        for percent in [0, 101, u8::MAX] {
+            assert!(quotas(percent).validate().is_err(), "{percent}");
+        }
+    }
 }
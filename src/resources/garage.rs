@@ -1,4 +1,8 @@
-use k8s_openapi::api::core::v1::SecretReference;
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{LifecycleHandler, Probe, SecretReference, VolumeProjection};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -15,6 +19,7 @@ use serde::{Deserialize, Serialize};
     printcolumn = r#"{ "name": "region", "type": "string", "description": "configured region", "jsonPath": ".spec.config.region" }"#,
     printcolumn = r#"{ "name": "replication", "type": "string", "description": "configured replication mode", "jsonPath": ".spec.config.replicationMode" }"#,
     printcolumn = r#"{ "name": "capacity", "type": "integer", "description": "garage capacity", "jsonPath": ".status.capacity" }"#,
+    printcolumn = r#"{ "name": "nodes", "type": "string", "description": "healthy/total nodes", "jsonPath": ".status.nodesFriendly" }"#,
     printcolumn = r#"{ "name": "status", "type": "string", "description": "garage status", "jsonPath": ".status.state" }"#
 )]
 #[serde(rename_all = "camelCase")]
@@ -26,10 +31,43 @@ pub struct GarageSpec {
     /// set up without manual intervention.
     ///
     /// If auto_layout is enabled, the operator will use the configuration supplied
-    /// in config to automatically layout the garage instance for you.
+    /// in config to automatically layout the garage instance for you. Otherwise, the
+    /// operator waits for the instance to be laid out manually, reporting the commands
+    /// to do so in the `ManualLayoutRequired` condition.
     #[serde(default)]
     pub auto_layout: bool,
 
+    /// Whether or not the operator manages the layout of the garage instance at all.
+    ///
+    /// When disabled, the operator never reads or changes the layout, and the instance is
+    /// considered ready as soon as garage is up, while buckets and access keys are still
+    /// managed. Layout is then left entirely to manual `garage layout` commands, so
+    /// `autoLayout` has no effect. Defaults to true.
+    #[serde(default = "defaults::manage_layout")]
+    pub manage_layout: bool,
+
+    /// The capacity to layout this garage instance with.
+    ///
+    /// By default, this is the sum of the capacities reported by the data backings. Some
+    /// storage, such as hostPath volumes or certain CSI drivers, never report a capacity, in
+    /// which case this is needed for the instance to be laid out. Data backings without a
+    /// reported capacity are given an even share of it in the garage config.
+    #[serde(default)]
+    pub layout_capacity: Option<Quantity>,
+
+    /// The version of garage to run, e.g. `v0.9.0`.
+    ///
+    /// Defaults to the version configured for the operator. Versions older than `v0.9.0` do
+    /// not serve the admin API used by the operator, so they are rejected.
+    #[serde(default)]
+    pub version: Option<String>,
+
+    /// When to pull the garage image.
+    ///
+    /// Defaults to the kubernetes default for the image tag.
+    #[serde(default)]
+    pub image_pull_policy: Option<ImagePullPolicy>,
+
     /// The config for this garage instance.
     ///
     /// Most of these options are mirrored from the
@@ -37,12 +75,92 @@ pub struct GarageSpec {
     #[serde(default)]
     pub config: GarageConfig,
 
+    /// Use an existing ConfigMap as the garage config instead of generating one.
+    ///
+    /// The config is used verbatim, so all of the options in `config` other than the ports
+    /// are ignored. It must read the RPC secret from `/secrets/rpc.key` and the admin token
+    /// from `/secrets/admin.key`, which are mounted by the operator.
+    #[serde(default)]
+    pub config_from: Option<ConfigMapReference>,
+
+    /// A template for the global alias of the buckets in this garage, e.g. `{namespace}-{name}`.
+    ///
+    /// Global aliases are shared by the whole garage, so buckets with the same name in
+    /// different namespaces would otherwise collide. `{namespace}` and `{name}` are replaced
+    /// with those of the `Bucket`. Defaults to the name of the `Bucket`.
+    #[serde(default)]
+    pub global_alias_template: Option<String>,
+
     /// Configuration for where to store the secrets needed for interacting with garage.
     #[serde(default)]
     pub secrets: GarageSecrets,
 
     /// The storage backing for this garage instance.
     pub storage: GarageStorage,
+
+    /// Whether or not to temporarily stop this garage instance.
+    ///
+    /// Suspending scales garage down to zero while keeping its config, secrets, and volumes
+    /// around. Buckets and access keys of a suspended garage are left untouched until it is
+    /// resumed.
+    #[serde(default)]
+    pub suspended: bool,
+
+    /// Whether or not to create a prometheus-operator `ServiceMonitor` for scraping the
+    /// metrics of garage.
+    ///
+    /// Requires the prometheus-operator CRDs to be installed in the cluster.
+    #[serde(default)]
+    pub create_service_monitor: bool,
+
+    /// The name of the ServiceAccount to run garage as.
+    ///
+    /// Defaults to the default ServiceAccount of the namespace.
+    #[serde(default)]
+    pub service_account_name: Option<String>,
+
+    /// Whether or not to mount the ServiceAccount token into the garage pod.
+    ///
+    /// Garage does not need to talk to kubernetes, so this defaults to false.
+    #[serde(default)]
+    pub automount_service_account_token: Option<bool>,
+
+    /// The name of the PriorityClass to schedule the garage pod with.
+    ///
+    /// Useful for making sure that storage is not preempted before stateless workloads.
+    #[serde(default)]
+    pub priority_class_name: Option<String>,
+
+    /// How long to give garage to shut down cleanly before it is killed, in seconds.
+    ///
+    /// Garage flushes its metadata on shutdown, so this defaults to 60.
+    #[serde(default = "defaults::termination_grace_period_seconds")]
+    pub termination_grace_period_seconds: i64,
+
+    /// An optional hook to run in the garage container before it is stopped.
+    #[serde(default)]
+    pub pre_stop: Option<LifecycleHandler>,
+
+    /// The probe used to check that garage has finished starting up.
+    ///
+    /// Garage can take a long time to open large metadata databases, so by default this
+    /// checks that the admin API accepts connections every 10 seconds for up to 10 minutes.
+    #[serde(default)]
+    pub startup_probe: Option<Probe>,
+
+    /// Override the entrypoint of the garage container.
+    ///
+    /// Useful for wrapping garage in a script or running patched builds.
+    #[serde(default)]
+    pub command: Vec<String>,
+
+    /// Override the arguments passed to the garage container.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Override the working directory of the garage container.
+    #[serde(default)]
+    pub working_dir: Option<String>,
 }
 
 /// Configuration for a garage instance.
@@ -61,9 +179,183 @@ pub struct GarageConfig {
     #[serde(default = "defaults::region")]
     pub region: String,
 
+    /// The zone to place this instance in when laying out the cluster.
+    ///
+    /// Unlike the region, which is only used by the S3 API, zones determine where garage
+    /// places copies of data. Defaults to the `topology.kubernetes.io/zone` label of the
+    /// node running garage, falling back to the region if the node is not labelled.
+    #[serde(default)]
+    pub zone: Option<String>,
+
+    /// Tags given to the node of this instance in the cluster layout.
+    ///
+    /// The operator only changes the roles of nodes carrying one of these tags or the
+    /// `garage-instance/<name>` tag, which is always added, so that nodes laid out by hand are
+    /// left untouched. Defaults to `owned-by/garage-operator`.
+    #[serde(default = "defaults::node_tags")]
+    pub node_tags: Vec<String>,
+
+    /// Labels of this garage to mirror onto its node in the cluster layout, as
+    /// `<label>=<value>` tags, so that garage's own tooling shows where the node came from.
+    ///
+    /// Tags are set when the node is first laid out, and do not mark the node as managed by
+    /// the operator. Garage has no tags for buckets or keys, so the labels of `Bucket` and
+    /// `AccessKey` resources are not propagated.
+    #[serde(default)]
+    pub node_tag_labels: Vec<String>,
+
+    /// The [root domain](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#s3_root_domain)
+    /// used for virtual-hosted-style S3 requests, e.g. `.s3.example.com`.
+    ///
+    /// Must start with a `.`. Required by SDKs configured to use vhost addressing.
+    #[serde(default)]
+    pub s3_api_root_domain: Option<String>,
+
+    /// Extra options to set in the [`[s3_api]`](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#s3_api)
+    /// section of the config.
+    ///
+    /// Values must be representable in TOML. Options managed by the operator cannot be set here.
+    /// Garage has no size options in this section, so values are rendered as given.
+    #[serde(default)]
+    #[schemars(schema_with = "super::arbitrary_map_schema")]
+    pub s3_api_options: BTreeMap<String, serde_json::Value>,
+
+    /// The address to bind the [admin API](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#admin_api_bind_addr)
+    /// to, e.g. `10.0.0.12` or `fd00::12`, or `podIP` to bind to the IP of the garage pod.
+    ///
+    /// Defaults to listening on every address. The operator talks to the admin API from
+    /// another pod, so loopback addresses are rejected. Pod IPs change whenever garage is
+    /// rescheduled, so `podIP` is filled in by an init container every time the pod starts.
+    #[serde(default)]
+    pub admin_bind_address: Option<String>,
+
+    /// An OpenTelemetry collector to [export traces to](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#admin_trace_sink),
+    /// e.g. `http://otel-collector:4317`.
+    #[serde(default)]
+    pub trace_sink: Option<String>,
+
+    /// The [database engine](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#db_engine)
+    /// used for storing metadata.
+    #[serde(default)]
+    pub db_engine: GarageDbEngine,
+
+    /// The amount of memory the database engine may use for caching.
+    ///
+    /// Maps to `sled_cache_capacity` for sled. Not supported for lmdb, whose `lmdb_map_size`
+    /// limits how large the database may grow rather than its memory use, and can be set
+    /// through `options` instead. Not supported for sqlite either.
+    #[serde(default)]
+    pub db_cache_size: Option<Quantity>,
+
+    /// The maximum amount of memory used for buffering the data blocks of requests in flight.
+    ///
+    /// Garage stops accepting new writes while this is exhausted, so it bounds how many
+    /// requests are handled concurrently. Maps to
+    /// [`block_ram_buffer_max`](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#block_ram_buffer_max)
+    /// and requires garage v1.0.0 or later.
+    #[serde(default)]
+    pub block_ram_buffer_max: Option<Quantity>,
+
+    /// Extra options to set at the top level of the config.
+    ///
+    /// Values must be representable in TOML. Options managed by the operator cannot be set
+    /// here. Useful tuning options include `block_size` and `compression_level`, which are
+    /// recognized by every supported version of garage.
+    ///
+    /// The size options `block_size` and `lmdb_map_size` are validated to be positive and may
+    /// be given as quantities such as `4Mi`, which are rendered as a number of bytes.
+    #[serde(default)]
+    #[schemars(schema_with = "super::arbitrary_map_schema")]
+    pub options: BTreeMap<String, serde_json::Value>,
+
     /// The type of [replication mode](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#replication_mode).
     #[serde(default = "defaults::replication")]
     pub replication_mode: String,
+
+    /// The [replication factor](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#replication_factor),
+    /// for versions of garage which configure it separately from the replication mode.
+    ///
+    /// Requires garage v1.0.0 or later. When set, `replicationMode` must either be left at
+    /// `none` or agree with the factor, and its `degraded` or `dangerous` suffix is kept as the
+    /// consistency mode. Since the operator lays out each garage as a single node in a single
+    /// zone, factors above 1 require `manageLayout` to be disabled.
+    #[serde(default)]
+    pub replication_factor: Option<u8>,
+
+    /// The [index document](https://garagehq.deuxfleurs.fr/documentation/reference-manual/configuration/#web_index)
+    /// served when requesting a directory of a bucket hosted as a website.
+    ///
+    /// Also used as the default index document for buckets that do not specify their own.
+    #[serde(default = "defaults::web_index")]
+    pub web_index: String,
+
+    /// The default document served when an object of a bucket hosted as a website is not found.
+    ///
+    /// Garage only supports error documents per bucket, so this is applied to every bucket
+    /// that does not specify its own.
+    #[serde(default)]
+    pub web_error_document: Option<String>,
+}
+
+/// The database engines supported by garage.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GarageDbEngine {
+    /// [LMDB](https://www.symas.com/lmdb), the recommended engine.
+    #[default]
+    Lmdb,
+
+    /// [Sled](https://sled.rs), which is deprecated.
+    Sled,
+
+    /// [SQLite](https://sqlite.org).
+    Sqlite,
+}
+
+impl std::fmt::Display for GarageDbEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GarageDbEngine::Lmdb => write!(f, "lmdb"),
+            GarageDbEngine::Sled => write!(f, "sled"),
+            GarageDbEngine::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// The [pull policies](https://kubernetes.io/docs/concepts/containers/images/#image-pull-policy)
+/// of a container image.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq)]
+pub enum ImagePullPolicy {
+    /// Always check the registry for a newer image.
+    Always,
+
+    /// Only pull the image if it is not already present on the node.
+    IfNotPresent,
+
+    /// Never pull the image, failing if it is not already present on the node.
+    Never,
+}
+
+impl std::fmt::Display for ImagePullPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImagePullPolicy::Always => write!(f, "Always"),
+            ImagePullPolicy::IfNotPresent => write!(f, "IfNotPresent"),
+            ImagePullPolicy::Never => write!(f, "Never"),
+        }
+    }
+}
+
+/// Reference to a key of a ConfigMap in the same namespace.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigMapReference {
+    /// The name of the ConfigMap.
+    pub name: String,
+
+    /// The key within the ConfigMap. Defaults to `garage.toml`.
+    #[serde(default = "defaults::config_key")]
+    pub key: String,
 }
 
 /// Secrets configuration for a Garage instance.
@@ -75,6 +367,24 @@ pub struct GarageSecrets {
 
     /// Reference to the inter-garage RPC secret.
     pub rpc: Option<SecretReference>,
+
+    /// Whether or not garage may start with secret files readable by other users.
+    ///
+    /// The operator mounts secrets with mode `0600`, but some volume plugins ignore it, which
+    /// makes garage refuse to start. Only enable this if the mode cannot be controlled.
+    pub allow_world_readable: bool,
+
+    /// Whether or not to mount the secrets together through a single projected volume.
+    ///
+    /// Unlike the default mounts, projected secrets are updated in place when rotated, and can
+    /// be combined with other sources through `projectedSources`.
+    pub projected: bool,
+
+    /// Additional sources to project into `/secrets` alongside the admin and RPC secrets.
+    ///
+    /// Only used when `projected` is enabled.
+    #[schemars(schema_with = "super::arbitrary_list_schema")]
+    pub projected_sources: Vec<VolumeProjection>,
 }
 
 /// Configuration for the backing store of a Garage instance.
@@ -85,7 +395,43 @@ pub struct GarageStorage {
     pub meta: String,
 
     /// List of backings to use for storing data.
+    ///
+    /// Garage needs at least one data directory, so this may only be empty when `sharedData`
+    /// is used. Backings must use the `Filesystem` volume mode. A single backing is passed to
+    /// garage as a plain `data_dir` path, while multiple backings are listed along with their
+    /// capacities.
+    #[serde(default)]
     pub data: Vec<String>,
+
+    /// A single backing split into multiple data directories using sub paths.
+    ///
+    /// These are used in addition to any backings specified in `data`.
+    #[serde(default)]
+    pub shared_data: Option<GarageSharedData>,
+}
+
+/// A single backing shared between multiple data directories of a Garage instance.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GarageSharedData {
+    /// The name of the backing to split.
+    pub claim: String,
+
+    /// The data directories to create within the backing.
+    pub paths: Vec<GarageSubPath>,
+}
+
+/// A data directory stored within a shared backing.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GarageSubPath {
+    /// The path within the backing to use for this data directory.
+    pub sub_path: String,
+
+    /// The capacity of this data directory.
+    ///
+    /// Since the backing is shared, this must be specified manually.
+    pub capacity: Quantity,
 }
 
 /// Port configuration of a Garage instance.
@@ -107,12 +453,53 @@ pub struct PortConfig {
 
 /// The status of the garage instance
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct GarageStatus {
     /// The total capacity of this instance
     pub capacity: i64,
 
     /// The current state of the garage instance
     pub state: GarageState,
+
+    /// A human readable message describing the current state, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+
+    /// The total number of nodes known to the cluster
+    #[serde(default)]
+    pub nodes_total: i64,
+
+    /// The number of nodes in the cluster which are up
+    #[serde(default)]
+    pub nodes_healthy: i64,
+
+    /// A friendly representation of the health of the nodes in the cluster.
+    ///
+    /// Format is healthy/total.
+    #[serde(default)]
+    pub nodes_friendly: String,
+
+    /// The amount of reconciles which have failed in a row
+    #[serde(default)]
+    pub consecutive_failures: u32,
+
+    /// The region which was last applied to the config of this garage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+
+    /// The generation of the spec which was last reconciled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+
+    /// Additional details about the state of the garage instance.
+    ///
+    /// `Running` reports whether the garage pod is running with its admin API listening, which
+    /// is waited for before laying out the instance. `ManualLayoutRequired` is set when `autoLayout` is disabled and the
+    /// cluster has not been laid out yet. `RegionChangeBlocked` is set when the region in the
+    /// spec differs from the one last applied, and the change has not been explicitly allowed
+    /// by setting the `deuxfleurs.fr/allow-region-change` annotation to the new region.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
 }
 
 /// The possible states of a `Garage`
@@ -130,6 +517,25 @@ pub enum GarageState {
 
     /// The garage instance encountered an error.
     Errored,
+
+    /// The garage instance is scaled down until it is resumed.
+    Suspended,
+}
+
+impl GarageStatus {
+    /// The condition set while a garage without auto-layout is waiting to be laid out by hand
+    pub const MANUAL_LAYOUT_REQUIRED: &'static str = "ManualLayoutRequired";
+
+    /// The condition reporting whether a garage pod is running with its admin API listening
+    pub const RUNNING: &'static str = "Running";
+
+    /// The condition set while a change of region is waiting to be allowed
+    pub const REGION_CHANGE_BLOCKED: &'static str = "RegionChangeBlocked";
+
+    /// Look up a condition by its type
+    pub fn condition(&self, type_: &str) -> Option<&Condition> {
+        self.conditions.iter().find(|c| c.type_ == type_)
+    }
 }
 
 impl Default for GarageConfig {
@@ -137,11 +543,32 @@ impl Default for GarageConfig {
         Self {
             ports: Default::default(),
             region: defaults::region(),
+            zone: None,
+            node_tags: defaults::node_tags(),
+            node_tag_labels: Default::default(),
+            s3_api_root_domain: None,
+            s3_api_options: Default::default(),
+            admin_bind_address: None,
+            trace_sink: None,
+            db_engine: Default::default(),
+            db_cache_size: None,
+            block_ram_buffer_max: None,
+            options: Default::default(),
             replication_mode: defaults::replication(),
+            replication_factor: None,
+            web_index: defaults::web_index(),
+            web_error_document: None,
         }
     }
 }
 
+impl GarageConfig {
+    /// The zone used for laying out this instance, given the zone of the node running it
+    pub fn zone<'a>(&'a self, node_zone: Option<&'a str>) -> &'a str {
+        self.zone.as_deref().or(node_zone).unwrap_or(&self.region)
+    }
+}
+
 impl Default for PortConfig {
     fn default() -> Self {
         Self {
@@ -160,4 +587,19 @@ mod defaults {
     pub fn replication() -> String {
         "none".into()
     }
+    pub fn config_key() -> String {
+        "garage.toml".into()
+    }
+    pub fn web_index() -> String {
+        "index.html".into()
+    }
+    pub fn node_tags() -> Vec<String> {
+        vec!["owned-by/garage-operator".into()]
+    }
+    pub fn manage_layout() -> bool {
+        true
+    }
+    pub fn termination_grace_period_seconds() -> i64 {
+        60
+    }
 }
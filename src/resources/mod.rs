@@ -1,5 +1,6 @@
-use schemars::JsonSchema;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 mod access_key;
 mod bucket;
@@ -10,7 +11,7 @@ pub use bucket::*;
 pub use garage::*;
 
 /// Reference to a namespaced object
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct NamespacedReference {
     /// The name of the resource
@@ -19,3 +20,30 @@ pub struct NamespacedReference {
     /// The containing namespace.
     pub namespace: String,
 }
+
+/// Schema for a map of arbitrary values
+///
+/// Kubernetes requires fields of unknown structure to be explicitly marked as such.
+pub(crate) fn arbitrary_map_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "object",
+        "additionalProperties": {
+            "x-kubernetes-preserve-unknown-fields": true,
+        },
+    }))
+    .unwrap()
+}
+
+/// Schema for a list of objects of arbitrary structure
+///
+/// Used for lists of kubernetes types whose full schema would bloat the CRD.
+pub(crate) fn arbitrary_list_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "x-kubernetes-preserve-unknown-fields": true,
+        },
+    }))
+    .unwrap()
+}